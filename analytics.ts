@@ -81,10 +81,14 @@ function safeString(str): string | null {
 
 /**
  * Send the last information about this user browsing this
- * page
+ * page, using `navigator.sendBeacon` when it is available so
+ * the page can unload without waiting on the request
  * @param url The endpoint to send the exiting info to
  */
 export function sendExiting(url = '/analytics/exiting', info: ExitingInfo = new ExitingInfo()) {
+    if (navigator.sendBeacon && navigator.sendBeacon(url, JSON.stringify(info))) {
+        return;
+    }
     let xhr = new XMLHttpRequest();
     xhr.open(METHOD, url, false);
     xhr.setRequestHeader('Accept', 'application/json');
@@ -111,4 +115,38 @@ export class ExitingInfo {
             link_clicked: this.link_clicked,
         }
     }
-}
\ No newline at end of file
+}
+
+/**
+ * Record a custom event, like copying a code snippet, for
+ * the current visit
+ * @param name What happened
+ * @param properties Any extra details about the event
+ * @param url The endpoint to send the event to
+ */
+export function sendEvent(name: string, properties: {[key: string]: any} = null, url = '/analytics/event'): Promise<void> {
+    let reqInit = {
+        method: METHOD,
+        headers: {
+            'Content-Type': 'application/json'
+        },
+        body: JSON.stringify(new EventInfo(name, properties)),
+    };
+    return fetch(url, reqInit).then(r => {
+        if (!r.ok) {
+            return Promise.reject('Failed to send event: ' + r.statusText);
+        }
+    });
+}
+
+/**
+ * A custom event that happened during this visit
+ */
+export class EventInfo {
+    constructor(
+        public name: string,
+        public properties: {[key: string]: any} = null,
+        public visit: string = safeString(localStorage.getItem(VISIT_KEY)),
+        public when = moment.utc(),
+    ) {}
+}
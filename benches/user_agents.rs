@@ -0,0 +1,50 @@
+//! Compares building a user agent parser for every header, as every
+//! landing used to, with the shared parser and its cache. Run with
+//! `cargo bench --bench user_agents`
+#[macro_use]
+extern crate criterion;
+extern crate lru_cache;
+extern crate uap_rust;
+
+// only the parser is used here and the module's tests aren't built
+#[allow(dead_code, unused_imports)]
+#[path = "../src/ua_parser.rs"]
+mod ua_parser;
+
+use criterion::{black_box, Criterion};
+use uap_rust::parser::Parser;
+
+use ua_parser::UserAgents;
+
+const HEADERS: [&str; 3] = [
+    "Mozilla/5.0 (Windows NT 10.0; Win64; x64; rv:120.0) Gecko/20100101 Firefox/120.0",
+    "Mozilla/5.0 (iPhone; CPU iPhone OS 16_5 like Mac OS X) AppleWebKit/605.1.15 (KHTML, like Gecko) Version/16.5 Mobile/15E148 Safari/604.1",
+    "Mozilla/5.0 (Macintosh; Intel Mac OS X 10_15_7) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/119.0.0.0 Safari/537.36",
+];
+
+fn per_request(c: &mut Criterion) {
+    let mut i = 0;
+    c.bench_function("parser per request", move |b| b.iter(|| {
+        i += 1;
+        let parser = Parser::new().unwrap();
+        black_box(parser.parse(HEADERS[i % HEADERS.len()].to_owned()))
+    }));
+}
+
+fn cached(c: &mut Criterion) {
+    let agents = UserAgents::new(1024).unwrap();
+    let mut i = 0;
+    c.bench_function("shared and cached", move |b| b.iter(|| {
+        i += 1;
+        black_box(agents.parse(HEADERS[i % HEADERS.len()]))
+    }));
+}
+
+criterion_group! {
+    name = benches;
+    // building the parser takes long enough that the default
+    // hundred samples would run for minutes
+    config = Criterion::default().sample_size(10);
+    targets = per_request, cached
+}
+criterion_main!(benches);
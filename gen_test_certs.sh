@@ -0,0 +1,15 @@
+# Regenerates the self-signed certificate in test_certs used by the
+# tls tests. The output is checked in, this only needs running again
+# once it expires. The same files can be used to try the tls modes
+# against a local Postgres server by pointing postgresql.conf at them
+#   ssl = on
+#   ssl_cert_file = '<repo>/test_certs/server.crt'
+#   ssl_key_file = '<repo>/test_certs/server.key'
+ROOT="$( cd "$( dirname "${BASH_SOURCE[0]}" )" >/dev/null && pwd )"
+mkdir -p "$ROOT/test_certs"
+openssl req -new -x509 -days 3650 -nodes \
+    -subj "/CN=localhost" \
+    -addext "subjectAltName=DNS:localhost" \
+    -keyout "$ROOT/test_certs/server.key" \
+    -out "$ROOT/test_certs/server.crt"
+chmod 600 "$ROOT/test_certs/server.key"
@@ -5,4 +5,4 @@ GRANT ALL ON SEQUENCE cookie_id TO carl;
 GRANT ALL ON TABLE ip_address TO carl;
 GRANT ALL ON SEQUENCE ip_address_id TO carl;
 
-ALTER DEFAULT PRIVILEGES FOR ROLE postgres GRANT ALL ON TABLES  TO carl;
\ No newline at end of file
+ALTER DEFAULT PRIVILEGES GRANT ALL ON TABLES TO carl;
\ No newline at end of file
@@ -1,8 +1,14 @@
-CREATE ROLE carl WITH
-    NOSUPERUSER
-    INHERIT
-    NOCREATEROLE
-    NOCREATEDB
-    LOGIN
-    NOREPLICATION
-    NOBYPASSRLS;
\ No newline at end of file
+DO $$
+BEGIN
+    IF NOT EXISTS (SELECT 1 FROM pg_roles WHERE rolname = 'carl') THEN
+        CREATE ROLE carl WITH
+            NOSUPERUSER
+            INHERIT
+            NOCREATEROLE
+            NOCREATEDB
+            LOGIN
+            NOREPLICATION
+            NOBYPASSRLS;
+    END IF;
+END
+$$;
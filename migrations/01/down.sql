@@ -0,0 +1,7 @@
+DROP FUNCTION IF EXISTS add_session(UUID, TEXT, TEXT, TEXT, TIMESTAMP WITH TIME ZONE, UUID, TEXT, TEXT);
+DROP FUNCTION IF EXISTS add_session_no_cookie(TEXT, TEXT, TEXT, TIMESTAMP WITH TIME ZONE, UUID, TEXT, TEXT);
+DROP FUNCTION IF EXISTS new_session(INTEGER, TEXT, TEXT, TIMESTAMP WITH TIME ZONE, UUID, TEXT, TEXT);
+DROP FUNCTION IF EXISTS ensure_ip_stored(TEXT, INTEGER);
+ALTER TABLE session DROP COLUMN IF EXISTS prev_visit_token;
+ALTER TABLE session DROP COLUMN IF EXISTS user_agent;
+ALTER TABLE session DROP COLUMN IF EXISTS site;
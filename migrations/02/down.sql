@@ -0,0 +1,5 @@
+DROP FUNCTION IF EXISTS unique_page_view_this_week();
+DROP TYPE IF EXISTS PageView;
+DROP FUNCTION IF EXISTS unique_visits_this_week();
+DROP FUNCTION IF EXISTS referrers_this_week();
+DROP TYPE IF EXISTS ReferrerCount;
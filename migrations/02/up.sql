@@ -4,7 +4,7 @@ CREATE TYPE ReferrerCount AS (
 );
 
 ALTER TYPE ReferrerCount
-    OWENER TO carl;
+    OWNER TO carl;
 
 --REFERRERS THIS WEEK
 CREATE OR REPLACE FUNCTION referrers_this_week()
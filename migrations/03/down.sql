@@ -0,0 +1,3 @@
+DROP FUNCTION IF EXISTS unique_page_views(INTEGER);
+DROP FUNCTION IF EXISTS unique_visits(INTEGER);
+DROP FUNCTION IF EXISTS unique_referrers(INTEGER);
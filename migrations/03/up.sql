@@ -0,0 +1,44 @@
+CREATE OR REPLACE FUNCTION unique_referrers(days_arg INTEGER)
+RETURNS SETOF ReferrerCount AS
+$$
+    SELECT referrer, count(page) as ct
+    FROM session
+    WHERE referrer IS NOT NULL
+    AND referrer NOT LIKE 'https://wiredforge%'
+    AND referrer NOT LIKE 'https://www.wiredforge%'
+    AND start > CURRENT_DATE - days_arg
+    GROUP BY referrer
+    ORDER BY ct DESC, referrer
+$$
+LANGUAGE sql;
+
+ALTER FUNCTION unique_referrers(INTEGER)
+    OWNER TO carl;
+
+CREATE OR REPLACE FUNCTION unique_visits(days_arg INTEGER)
+RETURNS SETOF BIGINT AS
+$$
+    SELECT count(cookie_id) as visit_count
+    FROM (SELECT DISTINCT cookie_id
+        FROM session
+    WHERE start > CURRENT_DATE - days_arg) a;
+$$
+LANGUAGE sql;
+
+ALTER FUNCTION unique_visits(INTEGER)
+    OWNER TO carl;
+
+CREATE OR REPLACE FUNCTION unique_page_views(days_arg INTEGER)
+RETURNS SETOF PageView AS
+$$
+    SELECT count(cookie_id) as view_count, page
+    FROM (SELECT DISTINCT cookie_id, page
+            FROM session
+    WHERE start > CURRENT_DATE - days_arg) a
+    GROUP BY page
+    ORDER BY view_count DESC, page;
+$$
+LANGUAGE sql;
+
+ALTER FUNCTION unique_page_views(INTEGER)
+    OWNER TO carl;
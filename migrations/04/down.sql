@@ -0,0 +1,3 @@
+DROP FUNCTION IF EXISTS unique_page_views(TIMESTAMP WITH TIME ZONE, TIMESTAMP WITH TIME ZONE);
+DROP FUNCTION IF EXISTS unique_visits(TIMESTAMP WITH TIME ZONE, TIMESTAMP WITH TIME ZONE);
+DROP FUNCTION IF EXISTS unique_referrers(TIMESTAMP WITH TIME ZONE, TIMESTAMP WITH TIME ZONE);
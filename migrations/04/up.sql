@@ -0,0 +1,52 @@
+-- the day count versions from 03 are replaced by these
+DROP FUNCTION IF EXISTS unique_referrers(INTEGER);
+DROP FUNCTION IF EXISTS unique_visits(INTEGER);
+DROP FUNCTION IF EXISTS unique_page_views(INTEGER);
+
+CREATE OR REPLACE FUNCTION unique_referrers(from_arg TIMESTAMP WITH TIME ZONE, to_arg TIMESTAMP WITH TIME ZONE)
+RETURNS SETOF ReferrerCount AS
+$$
+    SELECT referrer, count(page) as ct
+    FROM session
+    WHERE referrer IS NOT NULL
+    AND referrer NOT LIKE 'https://wiredforge%'
+    AND referrer NOT LIKE 'https://www.wiredforge%'
+    AND start >= from_arg
+    AND start < to_arg
+    GROUP BY referrer
+    ORDER BY ct DESC, referrer
+$$
+LANGUAGE sql;
+
+ALTER FUNCTION unique_referrers(TIMESTAMP WITH TIME ZONE, TIMESTAMP WITH TIME ZONE)
+    OWNER TO carl;
+
+CREATE OR REPLACE FUNCTION unique_visits(from_arg TIMESTAMP WITH TIME ZONE, to_arg TIMESTAMP WITH TIME ZONE)
+RETURNS SETOF BIGINT AS
+$$
+    SELECT count(cookie_id) as visit_count
+    FROM (SELECT DISTINCT cookie_id
+        FROM session
+    WHERE start >= from_arg
+    AND start < to_arg) a;
+$$
+LANGUAGE sql;
+
+ALTER FUNCTION unique_visits(TIMESTAMP WITH TIME ZONE, TIMESTAMP WITH TIME ZONE)
+    OWNER TO carl;
+
+CREATE OR REPLACE FUNCTION unique_page_views(from_arg TIMESTAMP WITH TIME ZONE, to_arg TIMESTAMP WITH TIME ZONE)
+RETURNS SETOF PageView AS
+$$
+    SELECT count(cookie_id) as view_count, page
+    FROM (SELECT DISTINCT cookie_id, page
+            FROM session
+    WHERE start >= from_arg
+    AND start < to_arg) a
+    GROUP BY page
+    ORDER BY view_count DESC, page;
+$$
+LANGUAGE sql;
+
+ALTER FUNCTION unique_page_views(TIMESTAMP WITH TIME ZONE, TIMESTAMP WITH TIME ZONE)
+    OWNER TO carl;
@@ -0,0 +1,6 @@
+DROP FUNCTION IF EXISTS site_summary(TIMESTAMP WITH TIME ZONE, TIMESTAMP WITH TIME ZONE);
+DROP FUNCTION IF EXISTS unique_page_views(TIMESTAMP WITH TIME ZONE, TIMESTAMP WITH TIME ZONE, TEXT);
+DROP FUNCTION IF EXISTS unique_visits(TIMESTAMP WITH TIME ZONE, TIMESTAMP WITH TIME ZONE, TEXT);
+DROP FUNCTION IF EXISTS unique_referrers(TIMESTAMP WITH TIME ZONE, TIMESTAMP WITH TIME ZONE, TEXT);
+DROP TYPE IF EXISTS SiteSummary;
+DROP FUNCTION IF EXISTS site_host(TEXT);
@@ -0,0 +1,102 @@
+-- The host of a url or site with any scheme, `www.`, port
+-- and path removed so `https://www.example.com/a` and
+-- `example.com` compare as equal
+CREATE OR REPLACE FUNCTION site_host(url_arg TEXT)
+RETURNS TEXT AS
+$$
+    SELECT regexp_replace(
+        regexp_replace(lower(url_arg), '^[a-z][a-z0-9+.-]*://', ''),
+        '^www\.|[:/?#].*$', '', 'g'
+    )
+$$
+LANGUAGE sql IMMUTABLE;
+
+ALTER FUNCTION site_host(TEXT)
+    OWNER TO carl;
+
+CREATE TYPE SiteSummary AS (
+    site TEXT,
+    visitors BIGINT,
+    sessions BIGINT
+);
+
+ALTER TYPE SiteSummary
+    OWNER TO carl;
+
+-- the versions from 04 without a site are replaced by these
+DROP FUNCTION IF EXISTS unique_referrers(TIMESTAMP WITH TIME ZONE, TIMESTAMP WITH TIME ZONE);
+DROP FUNCTION IF EXISTS unique_visits(TIMESTAMP WITH TIME ZONE, TIMESTAMP WITH TIME ZONE);
+DROP FUNCTION IF EXISTS unique_page_views(TIMESTAMP WITH TIME ZONE, TIMESTAMP WITH TIME ZONE);
+
+-- A NULL site_arg includes every site, referrers from the
+-- same site as the session they started are never counted.
+-- Sessions recorded before the site was sent fall back to
+-- the host of the page they landed on.
+CREATE OR REPLACE FUNCTION unique_referrers(from_arg TIMESTAMP WITH TIME ZONE, to_arg TIMESTAMP WITH TIME ZONE, site_arg TEXT)
+RETURNS SETOF ReferrerCount AS
+$$
+    SELECT referrer, count(page) as ct
+    FROM session
+    WHERE referrer IS NOT NULL
+    AND (site_arg IS NULL OR site_host(site) = site_host(site_arg))
+    AND site_host(referrer) IS DISTINCT FROM site_host(COALESCE(site, page))
+    AND start >= from_arg
+    AND start < to_arg
+    GROUP BY referrer
+    ORDER BY ct DESC, referrer
+$$
+LANGUAGE sql;
+
+ALTER FUNCTION unique_referrers(TIMESTAMP WITH TIME ZONE, TIMESTAMP WITH TIME ZONE, TEXT)
+    OWNER TO carl;
+
+CREATE OR REPLACE FUNCTION unique_visits(from_arg TIMESTAMP WITH TIME ZONE, to_arg TIMESTAMP WITH TIME ZONE, site_arg TEXT)
+RETURNS SETOF BIGINT AS
+$$
+    SELECT count(cookie_id) as visit_count
+    FROM (SELECT DISTINCT cookie_id
+        FROM session
+    WHERE (site_arg IS NULL OR site_host(site) = site_host(site_arg))
+    AND start >= from_arg
+    AND start < to_arg) a;
+$$
+LANGUAGE sql;
+
+ALTER FUNCTION unique_visits(TIMESTAMP WITH TIME ZONE, TIMESTAMP WITH TIME ZONE, TEXT)
+    OWNER TO carl;
+
+CREATE OR REPLACE FUNCTION unique_page_views(from_arg TIMESTAMP WITH TIME ZONE, to_arg TIMESTAMP WITH TIME ZONE, site_arg TEXT)
+RETURNS SETOF PageView AS
+$$
+    SELECT count(cookie_id) as view_count, page
+    FROM (SELECT DISTINCT cookie_id, page
+            FROM session
+    WHERE (site_arg IS NULL OR site_host(site) = site_host(site_arg))
+    AND start >= from_arg
+    AND start < to_arg) a
+    GROUP BY page
+    ORDER BY view_count DESC, page;
+$$
+LANGUAGE sql;
+
+ALTER FUNCTION unique_page_views(TIMESTAMP WITH TIME ZONE, TIMESTAMP WITH TIME ZONE, TEXT)
+    OWNER TO carl;
+
+-- Sessions recorded before the site was sent are grouped
+-- together under `unknown`
+CREATE OR REPLACE FUNCTION site_summary(from_arg TIMESTAMP WITH TIME ZONE, to_arg TIMESTAMP WITH TIME ZONE)
+RETURNS SETOF SiteSummary AS
+$$
+    SELECT COALESCE(site_host(site), 'unknown') as host,
+        count(DISTINCT cookie_id) as visitors,
+        count(*) as sessions
+    FROM session
+    WHERE start >= from_arg
+    AND start < to_arg
+    GROUP BY host
+    ORDER BY visitors DESC, host
+$$
+LANGUAGE sql;
+
+ALTER FUNCTION site_summary(TIMESTAMP WITH TIME ZONE, TIMESTAMP WITH TIME ZONE)
+    OWNER TO carl;
@@ -0,0 +1,6 @@
+DROP FUNCTION IF EXISTS internal_links(TIMESTAMP WITH TIME ZONE, TIMESTAMP WITH TIME ZONE, TEXT, INTEGER);
+DROP FUNCTION IF EXISTS page_engagement(TIMESTAMP WITH TIME ZONE, TIMESTAMP WITH TIME ZONE, TEXT);
+DROP TYPE IF EXISTS LinkCount;
+DROP TYPE IF EXISTS PageEngagement;
+DROP INDEX IF EXISTS session_prev_visit_token_idx;
+DROP INDEX IF EXISTS session_visit_token_idx;
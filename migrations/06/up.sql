@@ -0,0 +1,70 @@
+CREATE TYPE PageEngagement AS (
+    page TEXT,
+    sessions BIGINT,
+    median_ms BIGINT,
+    p90_ms BIGINT,
+    bounce_rate DOUBLE PRECISION
+);
+
+ALTER TYPE PageEngagement
+    OWNER TO carl;
+
+CREATE TYPE LinkCount AS (
+    link TEXT,
+    ct BIGINT
+);
+
+ALTER TYPE LinkCount
+    OWNER TO carl;
+
+-- page_engagement looks for a later session pointing back to each
+-- session and update_session finds sessions by visit_token
+CREATE INDEX session_visit_token_idx ON session (visit_token);
+CREATE INDEX session_prev_visit_token_idx ON session (prev_visit_token);
+
+-- Sessions that never reported a time on page are left out of
+-- the percentiles but still count towards the bounce rate, a
+-- session bounced when no later session points back to it
+CREATE OR REPLACE FUNCTION page_engagement(from_arg TIMESTAMP WITH TIME ZONE, to_arg TIMESTAMP WITH TIME ZONE, site_arg TEXT)
+RETURNS SETOF PageEngagement AS
+$$
+    SELECT page,
+        count(*) as sessions,
+        (percentile_cont(0.5) WITHIN GROUP (ORDER BY time_on_page))::BIGINT as median_ms,
+        (percentile_cont(0.9) WITHIN GROUP (ORDER BY time_on_page))::BIGINT as p90_ms,
+        avg(bounced::INTEGER)::DOUBLE PRECISION as bounce_rate
+    FROM (SELECT s.page, s.time_on_page,
+            NOT EXISTS (
+                SELECT 1
+                FROM session n
+                WHERE n.prev_visit_token = s.visit_token
+            ) as bounced
+        FROM session s
+        WHERE (site_arg IS NULL OR site_host(s.site) = site_host(site_arg))
+        AND s.start >= from_arg
+        AND s.start < to_arg) a
+    GROUP BY page
+    ORDER BY sessions DESC, page
+$$
+LANGUAGE sql;
+
+ALTER FUNCTION page_engagement(TIMESTAMP WITH TIME ZONE, TIMESTAMP WITH TIME ZONE, TEXT)
+    OWNER TO carl;
+
+CREATE OR REPLACE FUNCTION internal_links(from_arg TIMESTAMP WITH TIME ZONE, to_arg TIMESTAMP WITH TIME ZONE, site_arg TEXT, limit_arg INTEGER)
+RETURNS SETOF LinkCount AS
+$$
+    SELECT internal_link, count(*) as ct
+    FROM session
+    WHERE internal_link IS NOT NULL
+    AND (site_arg IS NULL OR site_host(site) = site_host(site_arg))
+    AND start >= from_arg
+    AND start < to_arg
+    GROUP BY internal_link
+    ORDER BY ct DESC, internal_link
+    LIMIT limit_arg
+$$
+LANGUAGE sql;
+
+ALTER FUNCTION internal_links(TIMESTAMP WITH TIME ZONE, TIMESTAMP WITH TIME ZONE, TEXT, INTEGER)
+    OWNER TO carl;
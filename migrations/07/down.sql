@@ -0,0 +1,2 @@
+DROP FUNCTION IF EXISTS path_sessions(TIMESTAMP WITH TIME ZONE, TIMESTAMP WITH TIME ZONE, TEXT);
+DROP TYPE IF EXISTS PathSession;
@@ -0,0 +1,26 @@
+CREATE TYPE PathSession AS (
+    cookie_id INTEGER,
+    visit_token UUID,
+    prev_visit_token UUID,
+    page TEXT
+);
+
+ALTER TYPE PathSession
+    OWNER TO carl;
+
+-- Every session in the window, ordered so each cookie's
+-- sessions are together and in the order they started
+CREATE OR REPLACE FUNCTION path_sessions(from_arg TIMESTAMP WITH TIME ZONE, to_arg TIMESTAMP WITH TIME ZONE, site_arg TEXT)
+RETURNS SETOF PathSession AS
+$$
+    SELECT cookie_id, visit_token, prev_visit_token, page
+    FROM session
+    WHERE (site_arg IS NULL OR site_host(site) = site_host(site_arg))
+    AND start >= from_arg
+    AND start < to_arg
+    ORDER BY cookie_id, start, id
+$$
+LANGUAGE sql;
+
+ALTER FUNCTION path_sessions(TIMESTAMP WITH TIME ZONE, TIMESTAMP WITH TIME ZONE, TEXT)
+    OWNER TO carl;
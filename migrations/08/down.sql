@@ -0,0 +1,2 @@
+DROP FUNCTION IF EXISTS cohort_retention(TIMESTAMP WITH TIME ZONE, TIMESTAMP WITH TIME ZONE, TEXT);
+DROP TYPE IF EXISTS CohortWeek;
@@ -0,0 +1,40 @@
+CREATE TYPE CohortWeek AS (
+    cohort DATE,
+    week INTEGER,
+    visitors BIGINT
+);
+
+ALTER TYPE CohortWeek
+    OWNER TO carl;
+
+-- Cookies are grouped by the week of their first session ever,
+-- only cohorts starting in the window are included. Week 0 is
+-- the size of the cohort and week n the number of its cookies
+-- with a session n weeks later, before to_arg
+CREATE OR REPLACE FUNCTION cohort_retention(from_arg TIMESTAMP WITH TIME ZONE, to_arg TIMESTAMP WITH TIME ZONE, site_arg TEXT)
+RETURNS SETOF CohortWeek AS
+$$
+    WITH first_seen AS (
+        SELECT cookie_id, date_trunc('week', min(start))::DATE as cohort
+        FROM session
+        WHERE (site_arg IS NULL OR site_host(site) = site_host(site_arg))
+        GROUP BY cookie_id
+    ), activity AS (
+        SELECT DISTINCT f.cookie_id, f.cohort,
+            (date_trunc('week', s.start)::DATE - f.cohort) / 7 as week
+        FROM session s
+        JOIN first_seen f ON f.cookie_id = s.cookie_id
+        WHERE (site_arg IS NULL OR site_host(s.site) = site_host(site_arg))
+        AND s.start < to_arg
+    )
+    SELECT cohort, week, count(*) as visitors
+    FROM activity
+    WHERE cohort >= date_trunc('week', from_arg)::DATE
+    AND cohort < to_arg
+    GROUP BY cohort, week
+    ORDER BY cohort, week
+$$
+LANGUAGE sql;
+
+ALTER FUNCTION cohort_retention(TIMESTAMP WITH TIME ZONE, TIMESTAMP WITH TIME ZONE, TEXT)
+    OWNER TO carl;
@@ -0,0 +1,5 @@
+DROP FUNCTION IF EXISTS event_counts(TIMESTAMP WITH TIME ZONE, TIMESTAMP WITH TIME ZONE, TEXT);
+DROP FUNCTION IF EXISTS add_event(UUID, TEXT, JSONB, TIMESTAMP WITH TIME ZONE);
+DROP TYPE IF EXISTS EventCount;
+DROP TABLE IF EXISTS event;
+DROP SEQUENCE IF EXISTS event_id;
@@ -0,0 +1,71 @@
+CREATE SEQUENCE event_id
+    START WITH 1
+    INCREMENT BY 1
+    NO MINVALUE
+    NO MAXVALUE
+    CACHE 1;
+
+CREATE TABLE event (
+    id INTEGER DEFAULT nextval('event_id'::regclass) NOT NULL,
+    session_id INTEGER NOT NULL,
+    name CHARACTER VARYING(255) NOT NULL,
+    properties JSONB,
+    occurred TIMESTAMP WITH TIME ZONE NOT NULL,
+    recorded TIMESTAMP WITH TIME ZONE DEFAULT current_timestamp NOT NULL
+);
+
+ALTER TABLE ONLY event
+    ADD CONSTRAINT event_key
+    PRIMARY KEY (id);
+
+ALTER TABLE ONLY event
+    ADD CONSTRAINT event_session_fkey
+    FOREIGN KEY (session_id)
+    REFERENCES session(id);
+
+CREATE INDEX event_occurred_idx ON event (occurred);
+
+GRANT ALL ON TABLE event TO carl;
+GRANT ALL ON SEQUENCE event_id TO carl;
+
+CREATE TYPE EventCount AS (
+    name TEXT,
+    page TEXT,
+    ct BIGINT
+);
+
+ALTER TYPE EventCount
+    OWNER TO carl;
+
+-- Returns NULL when no session has the visit token
+CREATE OR REPLACE FUNCTION add_event(visit_arg UUID, name_arg TEXT, properties_arg JSONB, when_arg TIMESTAMP WITH TIME ZONE)
+RETURNS INTEGER AS
+$$
+    INSERT INTO event (session_id, name, properties, occurred)
+    SELECT id, name_arg, properties_arg, when_arg
+    FROM session
+    WHERE visit_token = visit_arg
+    LIMIT 1
+    RETURNING id
+$$
+LANGUAGE sql;
+
+ALTER FUNCTION add_event(UUID, TEXT, JSONB, TIMESTAMP WITH TIME ZONE)
+    OWNER TO carl;
+
+CREATE OR REPLACE FUNCTION event_counts(from_arg TIMESTAMP WITH TIME ZONE, to_arg TIMESTAMP WITH TIME ZONE, site_arg TEXT)
+RETURNS SETOF EventCount AS
+$$
+    SELECT e.name, s.page, count(*) as ct
+    FROM event e
+    JOIN session s ON s.id = e.session_id
+    WHERE (site_arg IS NULL OR site_host(s.site) = site_host(site_arg))
+    AND e.occurred >= from_arg
+    AND e.occurred < to_arg
+    GROUP BY e.name, s.page
+    ORDER BY ct DESC, e.name, s.page
+$$
+LANGUAGE sql;
+
+ALTER FUNCTION event_counts(TIMESTAMP WITH TIME ZONE, TIMESTAMP WITH TIME ZONE, TEXT)
+    OWNER TO carl;
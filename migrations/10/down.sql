@@ -0,0 +1,8 @@
+DROP FUNCTION IF EXISTS campaign_summary(TIMESTAMP WITH TIME ZONE, TIMESTAMP WITH TIME ZONE, TEXT);
+DROP FUNCTION IF EXISTS add_session(UUID, TEXT, JSONB);
+DROP FUNCTION IF EXISTS add_session_no_cookie(TEXT, JSONB);
+DROP FUNCTION IF EXISTS new_session(INTEGER, JSONB);
+DROP TYPE IF EXISTS CampaignSummary;
+ALTER TABLE session DROP COLUMN IF EXISTS utm_campaign;
+ALTER TABLE session DROP COLUMN IF EXISTS utm_medium;
+ALTER TABLE session DROP COLUMN IF EXISTS utm_source;
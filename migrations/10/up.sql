@@ -0,0 +1,119 @@
+ALTER TABLE session ADD COLUMN utm_source VARCHAR(255) NULL;
+ALTER TABLE session ADD COLUMN utm_medium VARCHAR(255) NULL;
+ALTER TABLE session ADD COLUMN utm_campaign VARCHAR(255) NULL;
+
+CREATE TYPE CampaignSummary AS (
+    source TEXT,
+    medium TEXT,
+    campaign TEXT,
+    sessions BIGINT,
+    visitors BIGINT,
+    avg_time_on_page_ms BIGINT
+);
+
+ALTER TYPE CampaignSummary
+    OWNER TO carl;
+
+-- The session's own columns are passed as one jsonb object keyed
+-- by column name, so a column added to session later is written
+-- without replacing these functions again
+DROP FUNCTION IF EXISTS add_session(UUID, TEXT, TEXT, TEXT, TIMESTAMP WITH TIME ZONE, UUID, TEXT, TEXT);
+DROP FUNCTION IF EXISTS add_session_no_cookie(TEXT, TEXT, TEXT, TIMESTAMP WITH TIME ZONE, UUID, TEXT, TEXT);
+DROP FUNCTION IF EXISTS new_session(INTEGER, TEXT, TEXT, TIMESTAMP WITH TIME ZONE, UUID, TEXT, TEXT);
+
+CREATE OR REPLACE FUNCTION public.add_session(
+	token_arg uuid,
+	ip_arg text,
+	entry_arg jsonb
+    )
+    RETURNS initial_response
+    LANGUAGE 'plpgsql'
+    COST 100
+    VOLATILE 
+AS $BODY$
+DECLARE ret initial_response;
+DECLARE new_cookie tp_cookie;
+BEGIN
+    CASE WHEN token_arg IS NULL THEN
+        ret := (SELECT add_session_no_cookie(ip_arg, entry_arg));
+    ELSE
+        SELECT id, cookie
+            INTO new_cookie.id, new_cookie.token
+        FROM cookie
+        WHERE cookie.cookie = token_arg;
+
+        CASE WHEN new_cookie.id IS NULL THEN
+            new_cookie := (SELECT get_cookie_for_ip(ip_arg));
+        ELSE
+
+        END CASE;
+        ret.visit = (SELECT new_session(new_cookie.id, entry_arg));
+        ret.token = new_cookie.token;
+        PERFORM ensure_ip_stored(ip_arg, new_cookie.id);
+    END CASE;
+
+    RETURN ret;
+END;
+$BODY$;
+
+CREATE OR REPLACE FUNCTION public.add_session_no_cookie(
+	ip_arg text,
+	entry_arg jsonb
+    )
+    RETURNS initial_response
+    LANGUAGE 'plpgsql'
+    COST 100
+    VOLATILE 
+AS $BODY$
+DECLARE ret initial_response;
+DECLARE new_cookie tp_cookie;
+BEGIN
+    new_cookie = (SELECT get_cookie_for_ip(ip_arg));
+    ret.visit = new_session(new_cookie.id, entry_arg);
+    ret.token = new_cookie.token;
+    RETURN ret;
+END;
+$BODY$;
+
+-- The id, cookie and visit token are always set here, whatever
+-- entry_arg holds for them
+CREATE OR REPLACE FUNCTION public.new_session(
+	cookie_id_arg integer,
+	entry_arg jsonb)
+    RETURNS uuid
+    LANGUAGE 'plpgsql'
+    COST 100
+    VOLATILE 
+AS $BODY$
+DECLARE entry session;
+BEGIN
+    entry := jsonb_populate_record(NULL::session, entry_arg);
+    entry.id := nextval('session_id');
+    entry.cookie_id := cookie_id_arg;
+    entry.visit_token := uuid_generate_v4();
+    INSERT INTO session SELECT (entry).*;
+    RETURN entry.visit_token;
+END;
+$BODY$;
+
+-- Only sessions that landed with at least one utm parameter are
+-- counted, the average leaves out sessions without a time on page
+CREATE OR REPLACE FUNCTION campaign_summary(from_arg TIMESTAMP WITH TIME ZONE, to_arg TIMESTAMP WITH TIME ZONE, site_arg TEXT)
+RETURNS SETOF CampaignSummary AS
+$$
+    SELECT utm_source, utm_medium, utm_campaign,
+        count(*) as sessions,
+        count(DISTINCT cookie_id) as visitors,
+        avg(time_on_page)::BIGINT as avg_time_on_page_ms
+    FROM session
+    WHERE (utm_source IS NOT NULL OR utm_medium IS NOT NULL OR utm_campaign IS NOT NULL)
+    AND (site_arg IS NULL OR site_host(site) = site_host(site_arg))
+    AND start >= from_arg
+    AND start < to_arg
+    GROUP BY utm_source, utm_medium, utm_campaign
+    ORDER BY sessions DESC, utm_source, utm_medium, utm_campaign
+$$
+LANGUAGE sql;
+
+ALTER FUNCTION campaign_summary(TIMESTAMP WITH TIME ZONE, TIMESTAMP WITH TIME ZONE, TEXT)
+    OWNER TO carl;
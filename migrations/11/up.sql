@@ -0,0 +1,27 @@
+CREATE TYPE ReferrerSessions AS (
+    referrer TEXT,
+    site TEXT,
+    sessions BIGINT
+);
+
+ALTER TYPE ReferrerSessions
+    OWNER TO carl;
+
+-- Unlike unique_referrers this includes sessions without a
+-- referrer and internal referrers, they are classified by the
+-- server as direct and internal
+CREATE OR REPLACE FUNCTION referrer_sessions(from_arg TIMESTAMP WITH TIME ZONE, to_arg TIMESTAMP WITH TIME ZONE, site_arg TEXT)
+RETURNS SETOF ReferrerSessions AS
+$$
+    SELECT referrer, site, count(*) as sessions
+    FROM session
+    WHERE (site_arg IS NULL OR site_host(site) = site_host(site_arg))
+    AND start >= from_arg
+    AND start < to_arg
+    GROUP BY referrer, site
+    ORDER BY sessions DESC, referrer, site
+$$
+LANGUAGE sql;
+
+ALTER FUNCTION referrer_sessions(TIMESTAMP WITH TIME ZONE, TIMESTAMP WITH TIME ZONE, TEXT)
+    OWNER TO carl;
@@ -0,0 +1,5 @@
+DROP FUNCTION IF EXISTS referrer_sessions(TIMESTAMP WITH TIME ZONE, TIMESTAMP WITH TIME ZONE, TEXT);
+DROP TYPE IF EXISTS ReferrerSessions;
+ALTER TABLE session DROP COLUMN IF EXISTS search_terms;
+ALTER TABLE session DROP COLUMN IF EXISTS discussion;
+ALTER TABLE session DROP COLUMN IF EXISTS discussion_site;
@@ -0,0 +1,26 @@
+-- The thread and search terms are taken from the referrer before it
+-- is normalized, which usually drops the query they are found in
+ALTER TABLE session ADD COLUMN discussion_site VARCHAR(255) NULL;
+ALTER TABLE session ADD COLUMN discussion VARCHAR(255) NULL;
+ALTER TABLE session ADD COLUMN search_terms VARCHAR(255) NULL;
+
+DROP FUNCTION IF EXISTS referrer_sessions(TIMESTAMP WITH TIME ZONE, TIMESTAMP WITH TIME ZONE, TEXT);
+ALTER TYPE ReferrerSessions ADD ATTRIBUTE discussion_site TEXT;
+ALTER TYPE ReferrerSessions ADD ATTRIBUTE discussion TEXT;
+ALTER TYPE ReferrerSessions ADD ATTRIBUTE search_terms TEXT;
+
+CREATE OR REPLACE FUNCTION referrer_sessions(from_arg TIMESTAMP WITH TIME ZONE, to_arg TIMESTAMP WITH TIME ZONE, site_arg TEXT)
+RETURNS SETOF ReferrerSessions AS
+$$
+    SELECT referrer, site, count(*) as sessions, discussion_site, discussion, search_terms
+    FROM session
+    WHERE (site_arg IS NULL OR site_host(site) = site_host(site_arg))
+    AND start >= from_arg
+    AND start < to_arg
+    GROUP BY referrer, site, discussion_site, discussion, search_terms
+    ORDER BY sessions DESC, referrer, site, discussion_site, discussion, search_terms
+$$
+LANGUAGE sql;
+
+ALTER FUNCTION referrer_sessions(TIMESTAMP WITH TIME ZONE, TIMESTAMP WITH TIME ZONE, TEXT)
+    OWNER TO carl;
@@ -0,0 +1,7 @@
+DROP FUNCTION IF EXISTS user_agent_sessions(TIMESTAMP WITH TIME ZONE, TIMESTAMP WITH TIME ZONE, TEXT);
+DROP TYPE IF EXISTS UserAgentSessions;
+ALTER TABLE session DROP COLUMN IF EXISTS device_family;
+ALTER TABLE session DROP COLUMN IF EXISTS os_version;
+ALTER TABLE session DROP COLUMN IF EXISTS os_family;
+ALTER TABLE session DROP COLUMN IF EXISTS browser_major;
+ALTER TABLE session DROP COLUMN IF EXISTS browser_family;
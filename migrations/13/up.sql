@@ -0,0 +1,36 @@
+-- Sessions recorded before this migration keep the flattened
+-- "browser os device" string in user_agent and have no families,
+-- from here on user_agent holds the raw header
+ALTER TABLE session ADD COLUMN browser_family VARCHAR(255) NULL;
+ALTER TABLE session ADD COLUMN browser_major VARCHAR(255) NULL;
+ALTER TABLE session ADD COLUMN os_family VARCHAR(255) NULL;
+ALTER TABLE session ADD COLUMN os_version VARCHAR(255) NULL;
+ALTER TABLE session ADD COLUMN device_family VARCHAR(255) NULL;
+
+CREATE TYPE UserAgentSessions AS (
+    browser TEXT,
+    browser_major TEXT,
+    os TEXT,
+    os_version TEXT,
+    device TEXT,
+    sessions BIGINT
+);
+
+ALTER TYPE UserAgentSessions
+    OWNER TO carl;
+
+CREATE OR REPLACE FUNCTION user_agent_sessions(from_arg TIMESTAMP WITH TIME ZONE, to_arg TIMESTAMP WITH TIME ZONE, site_arg TEXT)
+RETURNS SETOF UserAgentSessions AS
+$$
+    SELECT browser_family, browser_major, os_family, os_version, device_family, count(*) as sessions
+    FROM session
+    WHERE (site_arg IS NULL OR site_host(site) = site_host(site_arg))
+    AND start >= from_arg
+    AND start < to_arg
+    GROUP BY browser_family, browser_major, os_family, os_version, device_family
+    ORDER BY sessions DESC
+$$
+LANGUAGE sql;
+
+ALTER FUNCTION user_agent_sessions(TIMESTAMP WITH TIME ZONE, TIMESTAMP WITH TIME ZONE, TEXT)
+    OWNER TO carl;
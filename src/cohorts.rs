@@ -0,0 +1,91 @@
+use chrono::{DateTime, Duration, NaiveDate, TimeZone, Utc};
+use postgres::Connection;
+
+use reports::{Cell, Column, Kind, Table};
+use window::ReportWindow;
+use super::Error;
+
+/// The most weeks after the first that get a column
+const MAX_WEEKS: i64 = 12;
+
+/// The retention table for cookies first seen during `window`, one
+/// row per week with the percentage of the cohort that came back in
+/// each of the following weeks
+pub(crate) fn table(conn: &Connection, window: &ReportWindow, site: Option<&str>, scope: &str) -> Result<Table, Error> {
+    let rows = conn.query("SELECT *
+                FROM cohort_retention($1, $2, $3)",
+                &[&window.from, &window.to, &site])?
+        .iter()
+        .map(|r| (r.get(0), r.get(1), r.get(2)))
+        .collect();
+    Ok(build(format!("{} Retention", scope), rows, window.to))
+}
+
+/// `rows` are `(cohort, week, visitors)` ordered by cohort and week,
+/// weeks that haven't started by `to` are left empty
+fn build(name: String, rows: Vec<(NaiveDate, i32, i64)>, to: DateTime<Utc>) -> Table {
+    let started = |cohort: NaiveDate, week: i64| Utc.from_utc_date(&(cohort + Duration::weeks(week))).and_hms(0, 0, 0) < to;
+    let weeks = rows.first()
+        .map(|&(first, _, _)| (1..=MAX_WEEKS).take_while(|&w| started(first, w)).count() as i64)
+        .unwrap_or(0);
+    let mut columns = vec![
+        Column::new("Cohort", Kind::Text),
+        Column::new("Visitors", Kind::Int),
+    ];
+    columns.extend((1..=weeks).map(|w| Column::new(&format!("Week {}", w), Kind::Percent)));
+    let mut table = Table::new(name, columns);
+    let mut rows = rows.into_iter().peekable();
+    while let Some((cohort, _, size)) = rows.next() {
+        let mut retained = vec![0; weeks as usize];
+        while let Some(&(next, week, visitors)) = rows.peek() {
+            if next != cohort {
+                break;
+            }
+            if week >= 1 && i64::from(week) <= weeks {
+                retained[week as usize - 1] = visitors;
+            }
+            rows.next();
+        }
+        let mut row = vec![Cell::Text(cohort.format("%Y-%m-%d").to_string()), Cell::Int(size)];
+        row.extend(retained.into_iter().enumerate().map(|(i, ct)| {
+            if started(cohort, i as i64 + 1) {
+                Cell::Percent(ct as f64 / size as f64)
+            } else {
+                Cell::Null
+            }
+        }));
+        table.rows.push(row);
+    }
+    table
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn weeks() {
+        let first = NaiveDate::from_ymd(2026, 9, 28);
+        let second = NaiveDate::from_ymd(2026, 10, 5);
+        let table = build("test".into(), vec![
+            (first, 0, 10),
+            (first, 1, 5),
+            (first, 2, 1),
+            (second, 0, 4),
+            (second, 1, 1),
+        ], Utc.ymd(2026, 10, 18).and_hms(12, 0, 0));
+        let headers: Vec<String> = table.columns.iter().map(Column::header).collect();
+        assert_eq!(headers, vec!["Cohort", "Visitors", "Week 1", "Week 2"]);
+        assert_eq!(table.rows, vec![
+            vec![Cell::Text("2026-09-28".into()), Cell::Int(10), Cell::Percent(0.5), Cell::Percent(0.1)],
+            vec![Cell::Text("2026-10-05".into()), Cell::Int(4), Cell::Percent(0.25), Cell::Null],
+        ]);
+    }
+
+    #[test]
+    fn empty() {
+        let table = build("test".into(), Vec::new(), Utc::now());
+        assert_eq!(table.columns.len(), 2);
+        assert!(table.rows.is_empty());
+    }
+}
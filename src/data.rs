@@ -1,32 +1,100 @@
 use super::{
+    BatchItem,
+    BatchResult,
     LandingInfo,
     ExitingInfo,
+    EventInfo,
     Error,
     InitialResponse,
 };
-use postgres::{
-    Connection,
-    TlsMode,
-};
-use toml::from_str;
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use postgres::{Connection, GenericConnection};
+use r2d2;
+use r2d2_postgres::PostgresConnectionManager;
 use uuid::Uuid;
-use reports::Table;
-use super::ReportWindow;
+use reports::{Cell, Column, Kind, Table};
+use cohorts;
+use config::DbConfig;
+use queue::Queue;
+use referrers::{self, Rules};
+use user_agents::{self, UserAgent};
+use tls;
+use window::ReportWindow;
+
+/// How many of the most clicked internal links are reported
+const MAX_INTERNAL_LINKS: i32 = 25;
+
+pub(crate) type Pool = r2d2::Pool<PostgresConnectionManager>;
+type PooledConnection = r2d2::PooledConnection<PostgresConnectionManager>;
 
-lazy_static! {
-    static ref CONFIG: DbConfig = from_str(include_str!("../dbinfo.toml")).expect("Unable to parse dbinfo.toml");
+/// Build the shared connection pool, connections are opened lazily
+/// so the server will still start while the database is unreachable
+pub(crate) fn create_pool(db: &DbConfig) -> Result<Pool, Error> {
+    let manager = PostgresConnectionManager::new(db.to_string(), tls::tls_mode(&db.tls)?)?;
+    let pool = r2d2::Pool::builder()
+        .max_size(db.pool_size)
+        .min_idle(Some(0))
+        .connection_timeout(Duration::from_secs(db.checkout_timeout))
+        .test_on_check_out(db.health_check)
+        .build_unchecked(manager);
+    Ok(pool)
 }
 
-pub(crate) fn add_entry(info: &LandingInfo, ip: &str, user_agent: &str) -> Result<InitialResponse, Error> {
+pub(crate) fn add_entry(pool: &Pool, info: &LandingInfo, ip: &str, user_agent: &str) -> Result<InitialResponse, Error> {
     debug!("add_entry {:#?},\n{}, {}", info, ip, user_agent);
-    let user_agent = parse_ua(user_agent).unwrap_or(user_agent.to_owned());
-    let conn = get_connection()?;
+    let agent = parse_ua(user_agent);
+    let conn = get_connection(pool)?;
+    insert_entry(&*conn, info, ip, user_agent, agent.as_ref())
+}
+
+/// The columns of a new session, `add_session` takes them as one
+/// jsonb object keyed by column name
+#[derive(Serialize)]
+struct SessionEntry<'a> {
+    referrer: Option<&'a str>,
+    page: &'a str,
+    start: DateTime<Utc>,
+    prev_visit_token: Option<Uuid>,
+    site: Option<&'a str>,
+    user_agent: &'a str,
+    utm_source: Option<&'a str>,
+    utm_medium: Option<&'a str>,
+    utm_campaign: Option<&'a str>,
+    discussion_site: Option<&'a str>,
+    discussion: Option<&'a str>,
+    search_terms: Option<&'a str>,
+    browser_family: Option<&'a str>,
+    browser_major: Option<&'a str>,
+    os_family: Option<&'a str>,
+    os_version: Option<&'a str>,
+    device_family: Option<&'a str>,
+}
+
+fn insert_entry(conn: &dyn GenericConnection, info: &LandingInfo, ip: &str, user_agent: &str, agent: Option<&UserAgent>) -> Result<InitialResponse, Error> {
+    let entry = ::serde_json::to_value(SessionEntry {
+        referrer: info.referrer.as_deref(),
+        page: &info.page,
+        start: info.when,
+        prev_visit_token: info.prev_visit,
+        site: info.site.as_deref(),
+        user_agent: &user_agents::stored(user_agent),
+        utm_source: info.campaign.source.as_deref(),
+        utm_medium: info.campaign.medium.as_deref(),
+        utm_campaign: info.campaign.name.as_deref(),
+        discussion_site: info.discussion.as_ref().map(|d| d.0),
+        discussion: info.discussion.as_ref().map(|d| d.1.as_str()),
+        search_terms: info.search_terms.as_deref(),
+        browser_family: agent.map(|a| a.browser.as_str()),
+        browser_major: agent.and_then(|a| a.browser_major.as_deref()),
+        os_family: agent.map(|a| a.os.as_str()),
+        os_version: agent.and_then(|a| a.os_version.as_deref()),
+        device_family: agent.map(|a| a.device.as_str()),
+    }).map_err(|e| Error::Other(format!("failed to serialize session {}", e)))?;
     let rows = conn.query("SELECT token, visit 
-                            FROM add_session($1, $2, $3, $4, $5, $6, $7, $8)", 
-                        &[&info.cookie, &ip, 
-                        &info.referrer, &info.page, 
-                        &info.when, &info.prev_visit, 
-                        &info.site, &user_agent])?;
+                            FROM add_session($1, $2, $3)", 
+                        &[&info.cookie, &ip, &entry])?;
     let only = rows.get(0);
     let token: Uuid = only.get(0);
     let visit: Uuid = only.get(1);
@@ -36,121 +104,442 @@ pub(crate) fn add_entry(info: &LandingInfo, ip: &str, user_agent: &str) -> Resul
     })
 }
 
-fn parse_ua(ua: &str) -> Result<String, Error> {
-    let parser = uap_rust::parser::Parser::new().map_err(|e| Error::Other(format!("failed to create UA parser {}", e)))?;
-    let ua = parser.parse(ua.to_owned());
-    Ok(format!("{} {} {}",  ua.user_agent.family, ua.os.family,ua.device.family))
+/// A header we can't parse still gets a session, just without
+/// the browser, os and device
+fn parse_ua(ua: &str) -> Option<UserAgent> {
+    user_agents::parse(ua)
+        .map_err(|e| warn!(target: "analytics:warn", "Unable to parse user agent {}", e))
+        .ok()
 }
 
-pub(crate) fn update_entry(info: &ExitingInfo) -> Result<(), Error> {
-    let conn = get_connection()?;
+pub(crate) fn update_entry(pool: &Pool, info: &ExitingInfo) -> Result<(), Error> {
+    let conn = get_connection(pool)?;
     conn.execute("SELECT update_session($1, $2, $3)", 
                 &[&info.visit, &info.time, 
                 &info.link_clicked])?;
     Ok(())
 }
 
-pub(crate) fn reports(window: &ReportWindow) -> Result<Vec<Table>, Error> {
-    let conn = get_connection()?;
-    let day_ct = window.to_sql();
-    let mut ref_table = Table::new(
-        format!("{} Day Referer Counts", day_ct),
-        vec![
-        "Referer".to_string(),
-        "Count".to_string(),
+/// Record a custom event against the session with the same visit
+/// token, returns false when there is no such session
+pub(crate) fn add_event(pool: &Pool, info: &EventInfo) -> Result<bool, Error> {
+    let conn = get_connection(pool)?;
+    insert_event(&*conn, info)
+}
+
+fn insert_event(conn: &dyn GenericConnection, info: &EventInfo) -> Result<bool, Error> {
+    let properties = info.properties.clone().map(::serde_json::Value::Object);
+    let rows = conn.query("SELECT add_event($1, $2, $3, $4)",
+                &[&info.visit, &info.name,
+                &properties, &info.when])?;
+    Ok(rows.iter().next().and_then(|r| r.get::<_, Option<i32>>(0)).is_some())
+}
+
+/// Apply every item in one transaction, each item gets its own
+/// savepoint so a failure only discards that item. Landing items
+/// all share the `ip` and `user_agent` of the batch request.
+/// Exiting items go through `queue` like `/analytics/exiting`,
+/// once the transaction has committed so they can follow a
+/// landing in the same batch
+pub(crate) fn apply_batch(pool: &Pool, queue: &Queue, items: Vec<BatchItem>, ip: &str, user_agent: &str) -> Result<Vec<BatchResult>, Error> {
+    let agent = parse_ua(user_agent);
+    let conn = get_connection(pool)?;
+    let trans = conn.transaction()?;
+    let mut ret = Vec::with_capacity(items.len());
+    let mut exits = Vec::new();
+    for item in items {
+        let savepoint = trans.savepoint("batch_item")?;
+        let res = match item {
+            BatchItem::Landing(ref info) => insert_entry(&savepoint, info, ip, user_agent, agent.as_ref()).map(BatchResult::landing),
+            BatchItem::Exiting(info) => {
+                exits.push((ret.len(), info));
+                Ok(BatchResult::ok())
+            },
+            BatchItem::Event(ref info) => insert_event(&savepoint, info).map(|found| if found {
+                BatchResult::ok()
+            } else {
+                BatchResult::error(404, format!("unknown visit {}", info.visit))
+            }),
+        };
+        match res {
+            Ok(res) => {
+                savepoint.commit()?;
+                ret.push(res);
+            },
+            Err(e) => {
+                warn!(target: "analytics:warn", "Error applying batch item {}", e);
+                savepoint.set_rollback();
+                ret.push(BatchResult::error(e.status(), e.body().error));
+            },
+        }
+    }
+    trans.commit()?;
+    for (i, info) in exits {
+        ret[i] = match queue.push(info) {
+            Ok(true) => BatchResult::ok(),
+            Ok(false) => BatchResult::error(503, "too many pending updates".to_string()),
+            Err(e) => BatchResult::error(e.status(), e.to_string()),
+        };
+    }
+    Ok(ret)
+}
+
+/// Build the report tables for `window`, when `site` is provided
+/// only that site's sessions are counted, otherwise every site is
+/// included along with a summary of each site. With `compare` the
+/// count in each table is paired with the previous period's.
+pub(crate) fn reports(pool: &Pool, rules: &Rules, window: &ReportWindow, site: Option<&str>, compare: bool) -> Result<Vec<Table>, Error> {
+    let conn = get_connection(pool)?;
+    let scope = window.scope(site);
+    let previous = if compare {
+        Some(window.previous().map_err(Error::Other)?)
+    } else {
+        None
+    };
+    let previous = previous.as_ref();
+    let mut ret = vec![
+        compared(window, previous, 1, |w| referrers_table(&conn, w, site, &scope))?,
+        compared(window, previous, 0, |w| visits_table(&conn, w, site, &scope))?,
+        compared(window, previous, 1, |w| pages_table(&conn, w, site, &scope))?,
+        not_compared(previous, engagement(&conn, window, site, &scope)?),
+        compared(window, previous, 1, |w| internal_links(&conn, w, site, &scope))?,
+        not_compared(previous, cohorts::table(&conn, window, site, &scope)?),
+        compared(window, previous, 2, |w| event_counts(&conn, w, site, &scope))?,
+        compared(window, previous, 3, |w| campaigns(&conn, w, site, &scope))?,
+    ];
+    ret.extend(referrers::tables(&conn, rules, window, previous, site, &scope)?);
+    ret.extend(user_agents::tables(&conn, window, previous, site, &scope)?);
+    if site.is_none() {
+        ret.push(compared(window, previous, 1, |w| site_summary(&conn, w))?);
+    }
+    Ok(ret)
+}
+
+/// Build a table for `window` and, when there is a `previous`
+/// period, for that as well so the count in column `count` can be
+/// compared
+fn compared<F>(window: &ReportWindow, previous: Option<&ReportWindow>, count: usize, build: F) -> Result<Table, Error>
+where F: Fn(&ReportWindow) -> Result<Table, Error> {
+    let table = build(window)?;
+    Ok(match previous {
+        Some(previous) => add_previous(table, build(previous)?, count),
+        None => table,
+    })
+}
+
+/// Mark a table that has nothing to compare with the previous
+/// period when comparing
+pub(crate) fn not_compared(previous: Option<&ReportWindow>, mut table: Table) -> Table {
+    if previous.is_some() {
+        table.note = Some("not compared with the previous period".to_string());
+    }
+    table
+}
+
+/// Time on page percentiles and bounce rate for each page, these
+/// aren't compared with the previous period
+fn engagement(conn: &Connection, window: &ReportWindow, site: Option<&str>, scope: &str) -> Result<Table, Error> {
+    let mut table = Table::new(format!("{} Time on Page", scope), vec![
+        Column::new("Page", Kind::Url),
+        Column::new("Sessions", Kind::Int),
+        Column::new("Median", Kind::Duration),
+        Column::new("90th Percentile", Kind::Duration),
+        Column::new("Bounce Rate", Kind::Percent),
     ]);
-    conn.query("SELECT * FROM 
-                unique_referrers($1)",
-                &[&day_ct])?
+    table.rows = conn.query("SELECT *
+                FROM page_engagement($1, $2, $3)",
+                &[&window.from, &window.to, &site])?
         .iter()
-        .for_each(|r|{
-            let mut referrer: String = r.get(0);
-            let ct: i64 = r.get(1);
-            if referrer.len() > 100 {
-                referrer = format!("{}...", &referrer[0..97]);
-            }
-            ref_table.rows.push(vec![referrer, ct.to_string()]);
-        });
-    let mut visits = Table::new(
-        format!("{} Day Visits", day_ct),
-        vec![
-        "Visit Count".to_string(),
+        .map(|r| vec![
+            Cell::Url(r.get(0)),
+            Cell::Int(r.get(1)),
+            r.get::<_, Option<i64>>(2).map(Cell::Duration).unwrap_or(Cell::Null),
+            r.get::<_, Option<i64>>(3).map(Cell::Duration).unwrap_or(Cell::Null),
+            Cell::Percent(r.get(4)),
+        ])
+        .collect();
+    Ok(table)
+}
+
+fn internal_links(conn: &Connection, window: &ReportWindow, site: Option<&str>, scope: &str) -> Result<Table, Error> {
+    let rows = conn.query("SELECT *
+                FROM internal_links($1, $2, $3, $4)",
+                &[&window.from, &window.to, &site, &MAX_INTERNAL_LINKS])?
+        .iter()
+        .map(|r| (r.get(0), r.get(1)))
+        .collect();
+    Ok(count_table(
+        format!("{} Internal Links", scope),
+        Column::new("Link", Kind::Url),
+        Column::new("Count", Kind::Int).unit("clicks"),
+        rows,
+    ))
+}
+
+fn event_counts(conn: &Connection, window: &ReportWindow, site: Option<&str>, scope: &str) -> Result<Table, Error> {
+    let mut table = Table::new(format!("{} Events", scope), vec![
+        Column::new("Event", Kind::Text),
+        Column::new("Page", Kind::Url),
+        Column::new("Count", Kind::Int),
     ]);
-    
-    conn.query("SELECT * 
-                FROM unique_visits($1)", 
-                &[&day_ct])?
+    table.rows = conn.query("SELECT *
+                FROM event_counts($1, $2, $3)",
+                &[&window.from, &window.to, &site])?
         .iter()
-        .for_each(|r| {
-            let visit_count: i64 = r.get(0);
-            visits.rows.push(vec![
-                visit_count.to_string(),
-            ]);
-        });
-    let mut views = Table::new(
-        format!("{}  Day Page Counts", day_ct),
-        vec![
-        "Page".to_string(),
-        "View Count".to_string(),    
+        .map(|r| vec![Cell::Text(r.get(0)), Cell::Url(r.get(1)), Cell::Int(r.get(2))])
+        .collect();
+    Ok(table)
+}
+
+/// Sessions that landed with `utm_*` parameters grouped by
+/// source, medium and campaign, the sessions are what gets
+/// compared with the previous period
+fn campaigns(conn: &Connection, window: &ReportWindow, site: Option<&str>, scope: &str) -> Result<Table, Error> {
+    let mut table = Table::new(format!("{} Campaigns", scope), vec![
+        Column::new("Source", Kind::Text),
+        Column::new("Medium", Kind::Text),
+        Column::new("Campaign", Kind::Text),
+        Column::new("Sessions", Kind::Int),
+        Column::new("Visitors", Kind::Int),
+        Column::new("Avg Time on Page", Kind::Duration),
+    ]);
+    let text = |value: Option<String>| value.map(Cell::Text).unwrap_or(Cell::Null);
+    table.rows = conn.query("SELECT *
+                FROM campaign_summary($1, $2, $3)",
+                &[&window.from, &window.to, &site])?
+        .iter()
+        .map(|r| vec![
+            text(r.get(0)),
+            text(r.get(1)),
+            text(r.get(2)),
+            Cell::Int(r.get(3)),
+            Cell::Int(r.get(4)),
+            r.get::<_, Option<i64>>(5).map(Cell::Duration).unwrap_or(Cell::Null),
+        ])
+        .collect();
+    Ok(table)
+}
+
+fn site_summary(conn: &Connection, window: &ReportWindow) -> Result<Table, Error> {
+    let mut table = Table::new(format!("{} Sites", window), vec![
+        Column::new("Site", Kind::Text),
+        Column::new("Visitors", Kind::Int),
+        Column::new("Sessions", Kind::Int),
     ]);
-    
-    conn.query("SELECT * 
-                FROM unique_page_views($1)", 
-                &[&day_ct])?
+    table.rows = conn.query("SELECT *
+                FROM site_summary($1, $2)",
+                &[&window.from, &window.to])?
+        .iter()
+        .map(|r| vec![Cell::Text(r.get(0)), Cell::Int(r.get(1)), Cell::Int(r.get(2))])
+        .collect();
+    Ok(table)
+}
+
+fn referrers_table(conn: &Connection, window: &ReportWindow, site: Option<&str>, scope: &str) -> Result<Table, Error> {
+    let rows = conn.query("SELECT *
+                FROM unique_referrers($1, $2, $3)",
+                &[&window.from, &window.to, &site])?
         .iter()
-        .for_each(|r| {
+        .map(|r| (r.get(0), r.get(1)))
+        .collect();
+    Ok(count_table(
+        format!("{} Referer Counts", scope),
+        Column::new("Referer", Kind::Url),
+        Column::new("Count", Kind::Int).unit("visits"),
+        rows,
+    ))
+}
+
+fn visits_table(conn: &Connection, window: &ReportWindow, site: Option<&str>, scope: &str) -> Result<Table, Error> {
+    let rows = conn.query("SELECT *
+                FROM unique_visits($1, $2, $3)",
+                &[&window.from, &window.to, &site])?;
+    let visits = rows.iter().next().map(|r| r.get(0)).unwrap_or(0);
+    let mut table = Table::new(format!("{} Visits", scope), vec![Column::new("Visit Count", Kind::Int)]);
+    table.rows.push(vec![Cell::Int(visits)]);
+    Ok(table)
+}
+
+fn pages_table(conn: &Connection, window: &ReportWindow, site: Option<&str>, scope: &str) -> Result<Table, Error> {
+    let rows = conn.query("SELECT *
+                FROM unique_page_views($1, $2, $3)",
+                &[&window.from, &window.to, &site])?
+        .iter()
+        .map(|r| {
             let view_count: i64 = r.get(0);
             let page: String = r.get(1);
-            views.rows.push(vec![
-                view_count.to_string(),
-                page,
-            ]);
-        });
-    Ok(vec![ref_table, visits, views])
+            (page, view_count)
+        })
+        .collect();
+    Ok(count_table(
+        format!("{} Page Counts", scope),
+        Column::new("Page", Kind::Url),
+        Column::new("View Count", Kind::Int),
+        rows,
+    ))
 }
 
-fn get_connection() -> Result<Connection, Error> {
-    let conn_str = CONFIG.to_string();
-    let ret = Connection::connect(conn_str.as_str(), TlsMode::None)?;
-    Ok(ret)
+/// Wrap a key in the cell type its column expects
+fn key_cell(column: &Column, key: String) -> Cell {
+    match column.kind {
+        Kind::Url => Cell::Url(key),
+        _ => Cell::Text(key),
+    }
 }
 
-#[derive(Deserialize)]
-struct DbConfig {
-    user: String,
-    domain: String,
-    port: usize,
-    password: String,
+/// A table of keys and how often each was counted
+pub(crate) fn count_table(name: String, key: Column, count: Column, rows: Vec<(String, i64)>) -> Table {
+    let mut table = Table::new(name, vec![key, count]);
+    table.rows = rows.into_iter()
+        .map(|(key, ct)| vec![key_cell(&table.columns[0], key), Cell::Int(ct)])
+        .collect();
+    table
 }
 
-impl ToString for DbConfig {
-    fn to_string(&self) -> String {
-        format!("postgres://{}:{}@{}:{}/analytics", self.user, self.password, self.domain, self.port)
+/// Follow the count in column `count` of each row in `current`
+/// with the count in `previous`, the change and the change as a
+/// percentage. Rows are matched on the columns before `count`,
+/// rows that only appear in the previous period are listed with
+/// a current count of 0 and nothing in the columns after it.
+pub(crate) fn add_previous(mut current: Table, previous: Table, count: usize) -> Table {
+    let int = |cell: &Cell| match *cell {
+        Cell::Int(i) => i,
+        _ => 0,
+    };
+    let mut previous: Vec<Option<Vec<Cell>>> = previous.rows.into_iter().map(Some).collect();
+    let mut rows = Vec::with_capacity(current.rows.len());
+    for mut row in current.rows {
+        let prev_ct = previous.iter_mut()
+            .find(|p| p.as_ref().map(|p| p[..count] == row[..count]).unwrap_or(false))
+            .and_then(Option::take)
+            .map(|p| int(&p[count]))
+            .unwrap_or(0);
+        let rest = row.split_off(count + 1);
+        let ct = row.pop().map(|c| int(&c)).unwrap_or(0);
+        row.extend(comparison_row(ct, prev_ct));
+        row.extend(rest);
+        rows.push(row);
     }
+    let after = current.columns.len() - count - 1;
+    for mut row in previous.into_iter().flatten() {
+        let prev_ct = int(&row[count]);
+        row.truncate(count);
+        row.extend(comparison_row(0, prev_ct));
+        let len = row.len() + after;
+        row.resize(len, Cell::Null);
+        rows.push(row);
+    }
+    let rest = current.columns.split_off(count + 1);
+    let count_column = current.columns.pop().expect("count column");
+    current.columns.extend(comparison_columns(count_column));
+    current.columns.extend(rest);
+    current.rows = rows;
+    current
+}
+
+/// The previous and change columns share the unit of `count`
+fn comparison_columns(count: Column) -> Vec<Column> {
+    let mut previous = Column::new("Previous", Kind::Int);
+    let mut change = Column::new("Change", Kind::Int).signed();
+    previous.unit = count.unit.clone();
+    change.unit = count.unit.clone();
+    vec![count, previous, change, Column::new("% Change", Kind::Percent).signed()]
+}
+
+fn comparison_row(current: i64, previous: i64) -> Vec<Cell> {
+    let change = current - previous;
+    let pct = if previous == 0 {
+        Cell::Null
+    } else {
+        Cell::Percent(change as f64 / previous as f64)
+    };
+    vec![
+        Cell::Int(current),
+        Cell::Int(previous),
+        Cell::Int(change),
+        pct,
+    ]
+}
+
+pub(crate) fn get_connection(pool: &Pool) -> Result<PooledConnection, Error> {
+    pool.get().map_err(Error::Pool)
 }
 
 #[cfg(test)]
 mod test {
     use uuid::Uuid;
+    use config::Config;
+    use reports::table;
+
+    lazy_static! {
+        static ref POOL: super::Pool = {
+            let config = Config::load(None).expect("Unable to load config");
+            let pool = super::create_pool(&config.db).expect("Unable to create pool");
+            ::migrations::up(&pool.get().expect("Unable to connect")).expect("Unable to migrate");
+            pool
+        };
+        static ref RULES: ::referrers::Rules = ::referrers::Rules::load(None).unwrap();
+    }
+
+    /// A database of its own for tests that need an empty schema,
+    /// created and migrated when built and dropped along with
+    /// everything in it when this is
+    struct FreshDatabase {
+        name: String,
+        pool: Option<super::Pool>,
+    }
+
+    impl FreshDatabase {
+        fn new() -> Self {
+            let config = Config::load(None).expect("Unable to load config");
+            let name = format!("analytics_test_{}", Uuid::new_v4().simple());
+            POOL.get().expect("Unable to connect")
+                .batch_execute(&format!("CREATE DATABASE {}", name))
+                .expect("Unable to create database");
+            let db = ::config::DbConfig {
+                database: name.clone(),
+                ..config.db
+            };
+            let pool = super::create_pool(&db).expect("Unable to create pool");
+            ::migrations::up(&pool.get().expect("Unable to connect")).expect("Unable to migrate");
+            Self {
+                name,
+                pool: Some(pool),
+            }
+        }
+
+        fn pool(&self) -> &super::Pool {
+            self.pool.as_ref().expect("pool already closed")
+        }
+    }
+
+    impl Drop for FreshDatabase {
+        fn drop(&mut self) {
+            // the pool's connections have to be closed first
+            self.pool.take();
+            let dropped = POOL.get().map_err(|e| e.to_string())
+                .and_then(|conn| conn.batch_execute(&format!("DROP DATABASE IF EXISTS {}", self.name)).map_err(|e| e.to_string()));
+            if let Err(e) = dropped {
+                error!(target: "analytics:error", "Unable to drop test database {}: {}", self.name, e);
+            }
+        }
+    }
+
     #[test]
     fn simple() {
         let initial = super::LandingInfo {
             referrer: Some("http://reddit.com/r/rust".into()),
-            page: "http://wiredforge.com/blog/getpid/index.html".into(),
-            cookie: None,
-            when: super::super::chrono::Utc::now(),
-            prev_visit: None,
-            site: Some("wiredforge.com".into())
+            ..::test_support::landing("wiredforge.com", "http://wiredforge.com/blog/getpid/index.html")
         };
         debug!(target: "analytics:test", "initial request: \n-----------\n{:?}\n----------", initial);
-        let res = super::add_entry(&initial, "0.0.0.0", "I'm a teapot").unwrap();
+        let res = super::add_entry(&POOL, &initial, "0.0.0.0", "I'm a teapot").unwrap();
         debug!(target: "analytics:test", "initial response: \n----------\n{:#?}\n-----------", res);
         let exit = super::ExitingInfo {
             visit: res.visit,
             time: 10000,
             link_clicked: None,
         };
-        super::update_entry(&exit).unwrap();
+        super::update_entry(&POOL, &exit).unwrap();
     }
 
     #[test]
@@ -158,15 +547,375 @@ mod test {
         let unknown_cookie = Uuid::new_v4();
         let landing = super::LandingInfo {
             referrer: Some("http://reddit.com/r/rust".into()),
-            page: "http://wiredforge.com/blog/getpid/index.html".into(),
             cookie: Some(unknown_cookie),
-            when: super::super::chrono::Utc::now(),
-            prev_visit: None,
-            site: Some("http://wiredforge.com".into()),
+            ..::test_support::landing("http://wiredforge.com", "http://wiredforge.com/blog/getpid/index.html")
         };
         debug!(target: "analytics:test", "initial request: \n-----------\n{:?}\n----------", landing);
-        let res = super::add_entry(&landing, "1.1.1.1", "I'm a teapot").unwrap();
+        let res = super::add_entry(&POOL, &landing, "1.1.1.1", "I'm a teapot").unwrap();
         debug!(target: "analytics:test", "result: {:?}", res);
         assert_ne!(unknown_cookie, res.token);
     }
+
+    #[test]
+    fn every_window() {
+        use reports::Cell;
+        use window::{ReportWindow, ReportQuery};
+        let landing = super::LandingInfo {
+            referrer: Some("http://reddit.com/r/rust".into()),
+            ..::test_support::landing("wiredforge.com", "http://wiredforge.com/blog/getpid")
+        };
+        let db = FreshDatabase::new();
+        super::add_entry(db.pool(), &landing, "2.2.2.2", "I'm a teapot").unwrap();
+        for window in &["day", "week", "month", "last-90d"] {
+            let window = ReportWindow::from_request(window, &ReportQuery::default()).unwrap();
+            for &compare in &[false, true] {
+                let tables = super::reports(db.pool(), &RULES, &window, None, compare).unwrap();
+                let (referrers, visits, views) = (table(&tables, "Referer Counts"), table(&tables, "Visits"), table(&tables, "Page Counts"));
+                assert!(referrers.rows.iter().any(|r| r[0] == Cell::Url("http://reddit.com/r/rust".into())));
+                assert_eq!(visits.rows.len(), 1);
+                assert!(views.rows.iter().any(|r| r[0] == Cell::Url("http://wiredforge.com/blog/getpid".into())));
+                assert!(tables[10].rows.iter().any(|r| r[1] == Cell::Text("/r/rust".into())));
+                if compare {
+                    assert_eq!(views.columns.len(), 5);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn site_scoped() {
+        use reports::Cell;
+        use window::{ReportWindow, ReportQuery};
+        let id = Uuid::new_v4().simple().to_string();
+        let site = format!("{}.example.com", id);
+        let page = format!("https://{}/post", site);
+        let landing = |site: &str, referrer: &str, page: &str| super::LandingInfo {
+            referrer: Some(referrer.into()),
+            ..::test_support::landing(site, page)
+        };
+        super::add_entry(&POOL, &landing(&site, "https://news.ycombinator.com/", &page), "3.3.3.3", "I'm a teapot").unwrap();
+        super::add_entry(&POOL, &landing(&format!("https://www.{}", site), &format!("https://www.{}/", site), &page), "3.3.3.3", "I'm a teapot").unwrap();
+        super::add_entry(&POOL, &landing("other.example.com", "https://lobste.rs/", "https://other.example.com/post"), "3.3.3.3", "I'm a teapot").unwrap();
+        let window = ReportWindow::from_request("day", &ReportQuery::default()).unwrap();
+        let tables = super::reports(&POOL, &RULES, &window, Some(&site), false).unwrap();
+        assert!(tables.iter().all(|t| t.name.starts_with(&site)));
+        assert!(!tables.iter().any(|t| t.name.ends_with(" Sites")));
+        assert_eq!(table(&tables, "Referer Counts").rows, vec![vec![Cell::Url("https://news.ycombinator.com/".into()), Cell::Int(1)]]);
+        assert_eq!(table(&tables, "Visits").rows, vec![vec![Cell::Int(1)]]);
+        assert_eq!(table(&tables, "Page Counts").rows, vec![vec![Cell::Url(page.clone()), Cell::Int(1)]]);
+        let retention = table(&tables, "Retention");
+        assert_eq!(retention.rows.len(), 1);
+        assert_eq!(retention.rows[0][1], Cell::Int(1));
+        assert_eq!(table(&tables, "Sources").rows, vec![
+            vec![Cell::Text("aggregator".into()), Cell::Text("Hacker News".into()), Cell::Int(1)],
+            vec![Cell::Text("internal".into()), Cell::Text(site.clone()), Cell::Int(1)],
+        ]);
+        let scoped = super::reports(&POOL, &RULES, &window, Some(&site), true).unwrap();
+        assert_eq!(table(&scoped, "Channels").rows[0], vec![
+            Cell::Text("aggregator".into()), Cell::Int(1), Cell::Int(0), Cell::Int(1), Cell::Null, Cell::Percent(0.5),
+        ]);
+        let all = super::reports(&POOL, &RULES, &window, None, false).unwrap();
+        let summary = table(&all, "Sites");
+        assert!(summary.rows.contains(&vec![Cell::Text(site.clone()), Cell::Int(1), Cell::Int(2)]));
+        assert!(!table(&all, "Referer Counts").rows.iter().any(|r| r[0] == Cell::Url(format!("https://www.{}/", site))));
+        let compared = super::reports(&POOL, &RULES, &window, None, true).unwrap();
+        assert!(table(&compared, "Sites").rows.contains(&vec![
+            Cell::Text(site.clone()), Cell::Int(1), Cell::Int(0), Cell::Int(1), Cell::Null, Cell::Int(2),
+        ]));
+        assert!(table(&compared, "Retention").note.is_some());
+    }
+
+    #[test]
+    fn self_referral_without_site() {
+        use reports::Cell;
+        use window::{ReportWindow, ReportQuery};
+        let host = format!("{}.example.com", Uuid::new_v4().simple());
+        let landing = |referrer: String| super::LandingInfo {
+            referrer: Some(referrer),
+            site: None,
+            ..::test_support::landing(&host, &format!("https://{}/post", host))
+        };
+        let internal = format!("https://www.{}/", host);
+        let external = format!("https://{}.example.org/", Uuid::new_v4().simple());
+        super::add_entry(&POOL, &landing(internal.clone()), "4.4.4.4", "I'm a teapot").unwrap();
+        super::add_entry(&POOL, &landing(external.clone()), "4.4.4.4", "I'm a teapot").unwrap();
+        let window = ReportWindow::from_request("day", &ReportQuery::default()).unwrap();
+        let tables = super::reports(&POOL, &RULES, &window, None, false).unwrap();
+        let referrers = &table(&tables, "Referer Counts").rows;
+        assert!(referrers.contains(&vec![Cell::Url(external), Cell::Int(1)]));
+        assert!(!referrers.iter().any(|r| r[0] == Cell::Url(internal.clone())));
+    }
+
+    #[test]
+    fn engagement() {
+        use reports::Cell;
+        use window::{ReportWindow, ReportQuery};
+        let site = format!("{}.example.com", Uuid::new_v4().simple());
+        let page = format!("https://{}/post", site);
+        let mut prev = None;
+        for (i, &time) in [1000, 2000, 3000, 4000, 100_000].iter().enumerate() {
+            let landing = super::LandingInfo {
+                // the first two visits are followed by another
+                prev_visit: if i == 1 || i == 2 { prev } else { None },
+                ..::test_support::landing(&site, &page)
+            };
+            let res = super::add_entry(&POOL, &landing, "4.4.4.4", "I'm a teapot").unwrap();
+            super::update_entry(&POOL, &super::ExitingInfo {
+                visit: res.visit,
+                time,
+                link_clicked: Some(format!("https://{}/next", site)),
+            }).unwrap();
+            prev = Some(res.visit);
+        }
+        let window = ReportWindow::from_request("day", &ReportQuery::default()).unwrap();
+        let tables = super::reports(&POOL, &RULES, &window, Some(&site), false).unwrap();
+        assert_eq!(table(&tables, "Time on Page").rows, vec![vec![
+            Cell::Url(page.clone()),
+            Cell::Int(5),
+            Cell::Duration(3000),
+            Cell::Duration(61_600),
+            Cell::Percent(0.6),
+        ]]);
+        assert_eq!(table(&tables, "Internal Links").rows, vec![vec![Cell::Url(format!("https://{}/next", site)), Cell::Int(5)]]);
+        assert!(table(&tables, "Time on Page").note.is_none());
+        let compared = super::reports(&POOL, &RULES, &window, Some(&site), true).unwrap();
+        assert!(table(&compared, "Time on Page").note.is_some());
+        assert_eq!(table(&compared, "Internal Links").rows, vec![vec![
+            Cell::Url(format!("https://{}/next", site)), Cell::Int(5), Cell::Int(0), Cell::Int(5), Cell::Null,
+        ]]);
+    }
+
+    #[test]
+    fn events() {
+        use reports::Cell;
+        use window::{ReportWindow, ReportQuery};
+        let site = format!("{}.example.com", Uuid::new_v4().simple());
+        let page = format!("https://{}/post", site);
+        let landing = ::test_support::landing(&site, &page);
+        let res = super::add_entry(&POOL, &landing, "6.6.6.6", "I'm a teapot").unwrap();
+        let mut event = super::EventInfo {
+            name: "copied code".into(),
+            properties: ::serde_json::from_str(r#"{"block": 2}"#).unwrap(),
+            visit: res.visit,
+            when: super::super::chrono::Utc::now(),
+        };
+        assert!(super::add_event(&POOL, &event).unwrap());
+        event.properties = None;
+        assert!(super::add_event(&POOL, &event).unwrap());
+        event.visit = Uuid::new_v4();
+        assert!(!super::add_event(&POOL, &event).unwrap());
+        let window = ReportWindow::from_request("day", &ReportQuery::default()).unwrap();
+        let tables = super::reports(&POOL, &RULES, &window, Some(&site), false).unwrap();
+        assert_eq!(table(&tables, "Events").rows, vec![vec![Cell::Text("copied code".into()), Cell::Url(page.clone()), Cell::Int(2)]]);
+        let tables = super::reports(&POOL, &RULES, &window, Some(&site), true).unwrap();
+        assert_eq!(table(&tables, "Events").rows, vec![vec![
+            Cell::Text("copied code".into()), Cell::Url(page), Cell::Int(2), Cell::Int(0), Cell::Int(2), Cell::Null,
+        ]]);
+    }
+
+    #[test]
+    fn campaigns() {
+        use reports::Cell;
+        use urls::campaign;
+        use window::{ReportWindow, ReportQuery};
+        let site = format!("{}.example.com", Uuid::new_v4().simple());
+        let page = format!("https://{}/post", site);
+        for (i, query) in ["?utm_source=hn&utm_campaign=launch", "?utm_source=hn&utm_campaign=launch", "?utm_source=newsletter", ""].iter().enumerate() {
+            let landing = super::LandingInfo {
+                campaign: campaign(&format!("{}{}", page, query)),
+                ..::test_support::landing(&site, &page)
+            };
+            let res = super::add_entry(&POOL, &landing, "8.8.8.8", "I'm a teapot").unwrap();
+            super::update_entry(&POOL, &super::ExitingInfo {
+                visit: res.visit,
+                time: 1000 * (i as i64 + 1),
+                link_clicked: None,
+            }).unwrap();
+        }
+        let window = ReportWindow::from_request("day", &ReportQuery::default()).unwrap();
+        let tables = super::reports(&POOL, &RULES, &window, Some(&site), false).unwrap();
+        assert_eq!(table(&tables, "Campaigns").rows, vec![
+            vec![Cell::Text("hn".into()), Cell::Null, Cell::Text("launch".into()), Cell::Int(2), Cell::Int(1), Cell::Duration(1500)],
+            vec![Cell::Text("newsletter".into()), Cell::Null, Cell::Null, Cell::Int(1), Cell::Int(1), Cell::Duration(3000)],
+        ]);
+        let tables = super::reports(&POOL, &RULES, &window, Some(&site), true).unwrap();
+        assert_eq!(table(&tables, "Campaigns").rows[1], vec![
+            Cell::Text("newsletter".into()), Cell::Null, Cell::Null, Cell::Int(1), Cell::Int(0), Cell::Int(1), Cell::Null, Cell::Int(1), Cell::Duration(3000),
+        ]);
+    }
+
+    #[test]
+    fn referrer_details() {
+        use config::UrlConfig;
+        use reports::Cell;
+        use urls::normalize_landing;
+        use window::{ReportWindow, ReportQuery};
+        let site = format!("{}.example.com", Uuid::new_v4().simple());
+        let page = format!("https://{}/post", site);
+        for referrer in &["https://news.ycombinator.com/item?id=123", "https://www.google.com/search?q=Rust+Warp"] {
+            let mut landing = super::LandingInfo {
+                referrer: Some(referrer.to_string()),
+                ..::test_support::landing(&site, &page)
+            };
+            normalize_landing(&mut landing, &UrlConfig::default());
+            super::add_entry(&POOL, &landing, "10.10.10.10", "I'm a teapot").unwrap();
+        }
+        let window = ReportWindow::from_request("day", &ReportQuery::default()).unwrap();
+        let tables = super::reports(&POOL, &RULES, &window, Some(&site), false).unwrap();
+        // the stored referrers lost the query the details came from
+        assert_eq!(table(&tables, "Referer Counts").rows.len(), 2);
+        assert!(!table(&tables, "Referer Counts").rows.iter().any(|r| match r[0] {
+            Cell::Url(ref url) => url.contains('?'),
+            _ => false,
+        }));
+        assert_eq!(table(&tables, "Discussions").rows, vec![vec![Cell::Text("Hacker News".into()), Cell::Text("/item?id=123".into()), Cell::Int(1)]]);
+        assert_eq!(table(&tables, "Search Terms").rows, vec![vec![Cell::Text("Google".into()), Cell::Text("rust warp".into()), Cell::Int(1)]]);
+        let tables = super::reports(&POOL, &RULES, &window, Some(&site), true).unwrap();
+        assert_eq!(table(&tables, "Discussions").rows, vec![vec![
+            Cell::Text("Hacker News".into()), Cell::Text("/item?id=123".into()), Cell::Int(1), Cell::Int(0), Cell::Int(1), Cell::Null,
+        ]]);
+    }
+
+    #[test]
+    fn user_agents() {
+        use reports::Cell;
+        use window::{ReportWindow, ReportQuery};
+        let site = format!("{}.example.com", Uuid::new_v4().simple());
+        let landing = ::test_support::landing(&site, &format!("https://{}/post", site));
+        let ua = "Mozilla/5.0 (Windows NT 10.0; Win64; x64; rv:120.0) Gecko/20100101 Firefox/120.0";
+        let res = super::add_entry(&POOL, &landing, "9.9.9.9", ua).unwrap();
+        let conn = POOL.get().unwrap();
+        let rows = conn.query("SELECT user_agent, browser_family, os_family FROM session WHERE visit_token = $1", &[&res.visit]).unwrap();
+        let row = rows.get(0);
+        assert_eq!(row.get::<_, String>(0), ua);
+        assert_eq!(row.get::<_, String>(1), "Firefox");
+        assert_eq!(row.get::<_, String>(2), "Windows 10");
+        let window = ReportWindow::from_request("day", &ReportQuery::default()).unwrap();
+        let tables = super::reports(&POOL, &RULES, &window, Some(&site), false).unwrap();
+        assert_eq!(table(&tables, "Browsers").rows, vec![vec![Cell::Text("Firefox".into()), Cell::Text("120".into()), Cell::Int(1), Cell::Percent(1.0)]]);
+        assert_eq!(table(&tables, "Operating Systems").rows[0][0], Cell::Text("Windows 10".into()));
+        assert_eq!(table(&tables, "Devices").rows, vec![vec![Cell::Text("desktop".into()), Cell::Int(1), Cell::Percent(1.0)]]);
+        let tables = super::reports(&POOL, &RULES, &window, Some(&site), true).unwrap();
+        assert_eq!(table(&tables, "Devices").rows, vec![vec![
+            Cell::Text("desktop".into()), Cell::Int(1), Cell::Int(0), Cell::Int(1), Cell::Null, Cell::Percent(1.0),
+        ]]);
+    }
+
+    #[test]
+    fn batch() {
+        use std::{fs, sync::Arc, thread, time::Duration};
+        use config::QueueConfig;
+        use queue::Queue;
+        use super::{BatchItem, ExitingInfo, EventInfo};
+        let site = format!("{}.example.com", Uuid::new_v4().simple());
+        let landing = ::test_support::landing(&site, &format!("https://{}/post", site));
+        let existing = super::add_entry(&POOL, &landing, "7.7.7.7", "I'm a teapot").unwrap();
+        let config = QueueConfig {
+            path: ::std::env::temp_dir().join(format!("analytics-batch-{}", Uuid::new_v4().simple())),
+            ..Default::default()
+        };
+        let queue = Arc::new(Queue::open(&config).unwrap());
+        let results = super::apply_batch(&POOL, &queue, vec![
+            BatchItem::Landing(landing),
+            BatchItem::Exiting(ExitingInfo {
+                visit: existing.visit,
+                time: 5000,
+                link_clicked: None,
+            }),
+            BatchItem::Event(EventInfo {
+                name: "lost".into(),
+                properties: None,
+                visit: Uuid::new_v4(),
+                when: super::super::chrono::Utc::now(),
+            }),
+            BatchItem::Event(EventInfo {
+                name: "x".repeat(300),
+                properties: None,
+                visit: existing.visit,
+                when: super::super::chrono::Utc::now(),
+            }),
+            BatchItem::Event(EventInfo {
+                name: "found".into(),
+                properties: None,
+                visit: existing.visit,
+                when: super::super::chrono::Utc::now(),
+            }),
+        ], "7.7.7.7", "I'm a teapot").unwrap();
+        let statuses: Vec<u16> = results.iter().map(|r| r.status).collect();
+        assert_eq!(statuses, vec![200, 200, 404, 500, 200]);
+        assert!(results[0].landing.is_some());
+        assert!(results[2].error.is_some());
+        // the exiting item waits in the queue until a worker applies it
+        assert_eq!(queue.depth(), 1);
+        let workers = Queue::start(&queue, &POOL, 1);
+        for _ in 0..50 {
+            if queue.depth() == 0 {
+                break;
+            }
+            thread::sleep(Duration::from_millis(100));
+        }
+        queue.stop();
+        for worker in workers {
+            worker.join().unwrap();
+        }
+        fs::remove_dir_all(&config.path).unwrap();
+        let conn = POOL.get().unwrap();
+        let rows = conn.query("SELECT time_on_page FROM session WHERE visit_token = $1", &[&existing.visit]).unwrap();
+        assert_eq!(rows.get(0).get::<_, Option<i64>>(0), Some(5000));
+    }
+
+    #[test]
+    fn comparison() {
+        use reports::{Cell, Column, Kind, Table};
+        let counts = |rows: Vec<(&str, i64)>| super::count_table(
+            "test".into(),
+            Column::new("Page", Kind::Url),
+            Column::new("Count", Kind::Int).unit("views"),
+            rows.into_iter().map(|(page, ct)| (page.to_string(), ct)).collect(),
+        );
+        let table = super::add_previous(counts(vec![("a", 15), ("b", 3)]), counts(vec![("c", 2), ("a", 10)]), 1);
+        assert_eq!(table.columns[2].header(), "Previous (views)");
+        let render = |table: &Table| -> Vec<Vec<String>> {
+            table.rows.iter()
+                .map(|row| row.iter().zip(table.columns.iter()).map(|(cell, col)| cell.display(col)).collect())
+                .collect()
+        };
+        assert_eq!(render(&table), vec![
+            vec!["a", "15", "10", "+5", "+50.0%"],
+            vec!["b", "3", "0", "+3", "n/a"],
+            vec!["c", "0", "2", "-2", "-100.0%"],
+        ]);
+        let sites = |rows: Vec<(&str, i64, i64)>| {
+            let mut table = Table::new("sites".into(), vec![
+                Column::new("Site", Kind::Text),
+                Column::new("Visitors", Kind::Int),
+                Column::new("Sessions", Kind::Int),
+            ]);
+            table.rows = rows.into_iter()
+                .map(|(site, visitors, sessions)| vec![Cell::Text(site.into()), Cell::Int(visitors), Cell::Int(sessions)])
+                .collect();
+            table
+        };
+        let table = super::add_previous(sites(vec![("a", 4, 6)]), sites(vec![("b", 1, 1), ("a", 2, 5)]), 1);
+        assert_eq!(table.columns.iter().map(|c| c.name.as_str()).collect::<Vec<_>>(),
+            vec!["Site", "Visitors", "Previous", "Change", "% Change", "Sessions"]);
+        assert_eq!(render(&table), vec![
+            vec!["a", "4", "2", "+2", "+100.0%", "6"],
+            vec!["b", "0", "1", "-1", "-100.0%", "n/a"],
+        ]);
+    }
+
+    #[test]
+    fn unavailable() {
+        let db = ::config::DbConfig {
+            port: 1,
+            checkout_timeout: 1,
+            ..Default::default()
+        };
+        let pool = super::create_pool(&db).unwrap();
+        match super::get_connection(&pool) {
+            Ok(_) => panic!("connected to a closed port"),
+            Err(e) => assert_eq!(e.status(), 503),
+        }
+    }
 }
\ No newline at end of file
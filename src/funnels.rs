@@ -0,0 +1,146 @@
+use postgres::Connection;
+
+use config::FunnelConfig;
+use data::{add_previous, get_connection, Pool};
+use glob::matches;
+use reports::{Cell, Column, Kind, Table};
+use window::ReportWindow;
+use super::Error;
+
+/// Build one table per funnel, each row is a step with the number
+/// of visitors that reached it, having already reached every step
+/// before it in order, during `window`. With `compare` the visitors
+/// at each step are paired with the previous period's.
+pub(crate) fn report(pool: &Pool, funnels: &[FunnelConfig], window: &ReportWindow, site: Option<&str>, compare: bool) -> Result<Vec<Table>, Error> {
+    if funnels.is_empty() {
+        return Ok(Vec::new());
+    }
+    let conn = get_connection(pool)?;
+    let scope = window.scope(site);
+    let ret = tables(&conn, funnels, window, site, &scope)?;
+    if !compare {
+        return Ok(ret);
+    }
+    let previous = window.previous().map_err(Error::Other)?;
+    let previous = tables(&conn, funnels, &previous, site, &scope)?;
+    Ok(ret.into_iter()
+        .zip(previous)
+        .map(|(current, previous)| add_previous(current, previous, 1))
+        .collect())
+}
+
+fn tables(conn: &Connection, funnels: &[FunnelConfig], window: &ReportWindow, site: Option<&str>, scope: &str) -> Result<Vec<Table>, Error> {
+    let sessions = sessions(conn, window, site)?;
+    Ok(funnels.iter().map(|funnel| {
+        let reached = reached(funnel, &sessions);
+        let mut table = Table::new(format!("{} {} Funnel", scope, funnel.name), vec![
+            Column::new("Step", Kind::Text),
+            Column::new("Visitors", Kind::Int),
+            Column::new("Conversion", Kind::Percent),
+            Column::new("Overall", Kind::Percent),
+            Column::new("Drop-off", Kind::Int).unit("visitors"),
+        ]);
+        let first = reached.first().cloned().unwrap_or(0);
+        let mut prev = None;
+        for (i, (step, &ct)) in funnel.steps.iter().zip(reached.iter()).enumerate() {
+            table.rows.push(vec![
+                Cell::Text(format!("{}. {}", i + 1, step)),
+                Cell::Int(ct),
+                ratio(ct, prev.unwrap_or(first)),
+                ratio(ct, first),
+                Cell::Int(prev.map(|p| p - ct).unwrap_or(0)),
+            ]);
+            prev = Some(ct);
+        }
+        table
+    }).collect())
+}
+
+/// Each cookie's pages in the order they were viewed
+fn sessions(conn: &Connection, window: &ReportWindow, site: Option<&str>) -> Result<Vec<(i32, String)>, Error> {
+    Ok(conn.query("SELECT cookie_id, page
+                FROM path_sessions($1, $2, $3)",
+                &[&window.from, &window.to, &site])?
+        .iter()
+        .map(|r| (r.get(0), r.get(1)))
+        .collect())
+}
+
+/// How many cookies reached each step of `funnel`, `sessions`
+/// must be grouped by cookie
+fn reached(funnel: &FunnelConfig, sessions: &[(i32, String)]) -> Vec<i64> {
+    let mut ret = vec![0; funnel.steps.len()];
+    let mut current = None;
+    let mut step = 0;
+    for &(cookie, ref page) in sessions {
+        if current != Some(cookie) {
+            current = Some(cookie);
+            step = 0;
+        }
+        if step < funnel.steps.len() && matches(&funnel.steps[step], page_path(page)) {
+            ret[step] += 1;
+            step += 1;
+        }
+    }
+    ret
+}
+
+fn ratio(n: i64, of: i64) -> Cell {
+    if of == 0 {
+        Cell::Null
+    } else {
+        Cell::Percent(n as f64 / of as f64)
+    }
+}
+
+/// The path of a page url without the query or fragment, pages are
+/// stored without a trailing slash so the root of a site is just
+/// its host
+fn page_path(page: &str) -> &str {
+    let rest = match page.find("://") {
+        Some(idx) => &page[idx + 3..],
+        None => page,
+    };
+    let path = match rest.find('/') {
+        Some(idx) => &rest[idx..],
+        None => "/",
+    };
+    match path.find(&['?', '#'][..]) {
+        Some(idx) => &path[..idx],
+        None => path,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn page_paths() {
+        assert_eq!(page_path("https://wiredforge.com"), "/");
+        assert_eq!(page_path("https://wiredforge.com/blog/post?x=1#top"), "/blog/post");
+        assert_eq!(page_path("/projects"), "/projects");
+    }
+
+    #[test]
+    fn steps() {
+        let funnel = FunnelConfig {
+            name: "projects".into(),
+            steps: vec!["/blog".into(), "/blog/*".into(), "/projects/*".into()],
+        };
+        let sessions: Vec<(i32, String)> = vec![
+            (1, "https://wiredforge.com/blog"),
+            (1, "https://wiredforge.com/blog/post"),
+            (1, "https://wiredforge.com/projects/analytics"),
+            // out of order, only the first step counts
+            (2, "https://wiredforge.com/blog/post"),
+            (2, "https://wiredforge.com/blog"),
+            (2, "https://wiredforge.com/projects/analytics"),
+            (3, "https://wiredforge.com/blog"),
+            (3, "https://wiredforge.com/about"),
+            (3, "https://wiredforge.com/blog/other"),
+            (4, "https://wiredforge.com/projects/analytics"),
+        ].into_iter().map(|(c, p)| (c, p.to_string())).collect();
+        assert_eq!(reached(&funnel, &sessions), vec![3, 2, 1]);
+    }
+}
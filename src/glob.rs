@@ -0,0 +1,47 @@
+/// Match `value` against a pattern where `*` matches any run of
+/// characters, including `/` and `.`, used for funnel steps,
+/// allowed query parameters and referrer hosts
+pub(crate) fn matches(pattern: &str, value: &str) -> bool {
+    let mut parts = pattern.split('*');
+    let first = parts.next().unwrap_or("");
+    if !value.starts_with(first) {
+        return false;
+    }
+    let mut rest = &value[first.len()..];
+    let parts: Vec<&str> = parts.collect();
+    let last = match parts.split_last() {
+        Some((last, middle)) => {
+            for part in middle {
+                match rest.find(part) {
+                    Some(idx) => rest = &rest[idx + part.len()..],
+                    None => return false,
+                }
+            }
+            last
+        },
+        None => return rest.is_empty(),
+    };
+    rest.ends_with(last)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn patterns() {
+        assert!(matches("/blog", "/blog"));
+        assert!(!matches("/blog", "/blog/post"));
+        assert!(matches("/blog/*", "/blog/post"));
+        assert!(matches("/blog/*", "/blog/"));
+        assert!(!matches("/blog/*", "/blog"));
+        assert!(matches("/*/index", "/a/b/index"));
+        assert!(matches("*", "/"));
+        assert!(matches("/a*b*c", "/abc"));
+        assert!(!matches("/a*b*c", "/acb"));
+        assert!(!matches("/a*aa", "/aa"));
+        assert!(matches("utm_*", "utm_source"));
+        assert!(matches("*.reddit.com", "old.reddit.com"));
+        assert!(!matches("*.reddit.com", "reddit.com"));
+    }
+}
@@ -20,34 +20,156 @@ extern crate tera;
 extern crate uap_rust;
 extern crate term_table;
 extern crate colored;
+extern crate clap;
+extern crate native_tls;
+extern crate r2d2;
+extern crate r2d2_postgres;
+extern crate futures;
+extern crate tokio;
+extern crate ctrlc;
+extern crate url;
+extern crate lru_cache;
 
 use std::{
     error::Error as StdError,
     num::ParseIntError,
+    sync::{Arc, Mutex},
 };
 
 use chrono::{DateTime, Utc};
 use uuid::Uuid;
+use futures::{Future, sync::oneshot};
+use serde::{Serialize, de::DeserializeOwned};
 use warp::{
+    Buf,
     Filter,
+    Rejection,
+    body::FullBody,
     http::{
+        Error as HttpError,
+        HeaderMap,
         Response,
     },
     reply::Reply,
 };
 use postgres::Error as PError;
+use r2d2::Error as PoolError;
+use native_tls::Error as TlsError;
 
+mod cohorts;
+mod config;
 mod data;
+mod funnels;
+mod glob;
+mod migrations;
+mod paths;
+mod queue;
+mod referrers;
 mod time_parsing;
+mod ua_parser;
+mod urls;
+mod user_agents;
 mod reports;
+#[cfg(test)]
+mod test_support;
+mod tls;
+mod validation;
+mod window;
 
-use reports::Table;
+use config::{Command, Config};
+use data::Pool;
+use queue::Queue;
+use referrers::Rules;
+use validation::{ErrorBody, Validate};
+use reports::{Format, Table};
+use urls::Campaign;
+use window::{ReportQuery, ReportWindow};
 
 fn main() {
     env_logger::init();
-    info!(target: "analytics:info", "Starting up");
+    let (config, command) = match Config::from_args() {
+        Ok(pair) => pair,
+        Err(e) => {
+            error!(target: "analytics:error", "Error loading config {}", e);
+            eprintln!("{}", e);
+            ::std::process::exit(1);
+        }
+    };
+    let res = match command {
+        Command::Serve => run(config),
+        Command::Migrate(cmd) => migrate(&config, cmd),
+    };
+    if let Err(e) = res {
+        error!(target: "analytics:error", "{}", e);
+        eprintln!("{}", e);
+        ::std::process::exit(1);
+    }
+}
+
+fn migrate(config: &Config, command: migrations::Command) -> Result<(), Error> {
+    let pool = data::create_pool(&config.db)?;
+    let conn = pool.get().map_err(Error::Pool)?;
+    match command {
+        migrations::Command::Up => {
+            let applied = migrations::up(&conn)?;
+            if applied.is_empty() {
+                println!("Schema is up to date");
+            }
+            for name in applied {
+                println!("applied {}", name);
+            }
+        },
+        migrations::Command::Down => match migrations::down(&conn)? {
+            Some(name) => println!("reverted {}", name),
+            None => println!("No migrations to revert"),
+        },
+        migrations::Command::Status => {
+            for (name, applied) in migrations::status(&conn)? {
+                match applied {
+                    Some(when) => println!("{:<24} applied {}", name, when),
+                    None => println!("{:<24} pending", name),
+                }
+            }
+        },
+        migrations::Command::Baseline(last) => {
+            for name in migrations::baseline(&conn, &last)? {
+                println!("marked {} as applied", name);
+            }
+        },
+    }
+    Ok(())
+}
+
+/// Refuse to serve requests against an out of date schema. When
+/// the database can't be reached the check is skipped so the
+/// server can still come up before postgres does
+fn check_schema(pool: &Pool) -> Result<(), Error> {
+    let conn = match pool.get() {
+        Ok(conn) => conn,
+        Err(e) => {
+            warn!(target: "analytics:warn", "Unable to verify the schema version {}", e);
+            return Ok(());
+        }
+    };
+    let pending = migrations::pending(&conn)?;
+    if pending.is_empty() {
+        return Ok(());
+    }
+    let names: Vec<&str> = pending.iter().map(|m| m.name).collect();
+    Err(Error::Other(format!("The database schema is behind, pending migrations: {}. Run `analytics migrate up` first", names.join(", "))))
+}
+
+fn run(config: Config) -> Result<(), Error> {
+    info!(target: "analytics:info", "Starting up on {}", config.server.bind);
+    let pool = data::create_pool(&config.db)?;
+    check_schema(&pool)?;
+    user_agents::init()?;
+    let queue = Arc::new(Queue::open(&config.queue)?);
+    let workers = Queue::start(&queue, &pool, config.queue.workers);
+    let rules = Arc::new(Rules::load(config.referrers.rules.as_deref())?);
+    let config = Arc::new(config);
     let cors = warp::cors()
-        .allow_origins(vec!["https://wiredforge.com", "https://github.io", "https://freemasen.github.io"])
+        .allow_origins(config.server.allowed_origins.iter().map(String::as_str))
         .allow_methods(vec!["POST", "OPTIONS"])
         .allow_headers(vec![
             "Host",
@@ -74,89 +196,280 @@ fn main() {
         Response::builder().status(200).body("")
     });
     let log = warp::log("analytics:log");
+    let with_config = {
+        let config = config.clone();
+        warp::any().map(move || config.clone())
+    };
+    let with_pool = warp::any().map(move || pool.clone());
+    let with_queue = {
+        let queue = queue.clone();
+        warp::any().map(move || queue.clone())
+    };
+    let with_rules = warp::any().map(move || rules.clone());
     let landing = warp::post2()
         .and(warp::path("landing"))
-        .and(warp::body::json())
+        .and(any_json(MAX_BODY_BYTES))
         .and(warp::header("x-client-address"))
         .and(warp::header("User-Agent"))
-        .map(landing_handler)
+        .and(with_pool.clone())
+        .and(with_config.clone())
+        .and_then(landing_handler)
         .with(log);
     let exiting = warp::post2()
         .and(warp::path("exiting"))
-        .and(warp::body::json())
-        .map(exiting_handler)
+        .and(any_json(MAX_BODY_BYTES))
+        .and(with_queue.clone())
+        .and_then(exiting_handler)
+        .with(log);
+    let event = warp::post2()
+        .and(warp::path("event"))
+        .and(any_json(MAX_BODY_BYTES))
+        .and(with_pool.clone())
+        .and_then(event_handler)
+        .with(log);
+    let batch = warp::post2()
+        .and(warp::path("batch"))
+        .and(any_json(MAX_BATCH_BYTES))
+        .and(warp::header("x-client-address"))
+        .and(warp::header("User-Agent"))
+        .and(with_pool.clone())
+        .and(with_queue.clone())
+        .and(with_config.clone())
+        .and_then(batch_handler)
         .with(log);
     let reporting_with_email = warp::get2()
         .and(warp::path("analytics"))
         .and(warp::path("reports"))
         .and(warp::path::param())
-        .map(|window: ReportWindow| reports_handler(window, true))
+        .and(warp::query())
+        .and(warp::header::headers_cloned())
+        .and(with_pool.clone())
+        .and(with_rules.clone())
+        .and(with_config.clone())
+        .and_then(|window: String, query: ReportQuery, headers: HeaderMap, pool: Pool, rules: Arc<Rules>, config: Arc<Config>| {
+            reports_handler(&window, &query, &headers, true, &pool, &rules, &config)
+        })
         .with(log);
+    // errors from the other `/analytics/...` reports would otherwise
+    // be replaced by this route's error for the window `analytics`
+    let window_param = warp::path::param().and_then(|window: String| if window == "analytics" {
+        Err(warp::reject::not_found())
+    } else {
+        Ok(window)
+    });
     let reporting_no_email = warp::get2()
+        .and(window_param)
+        .and(warp::query())
+        .and(warp::header::headers_cloned())
+        .and(with_pool.clone())
+        .and(with_rules.clone())
+        .and(with_config.clone())
+        .and_then(|window: String, query: ReportQuery, headers: HeaderMap, pool: Pool, rules: Arc<Rules>, config: Arc<Config>| {
+            reports_handler(&window, &query, &headers, false, &pool, &rules, &config)
+        })
+        .with(log);
+    let metrics = warp::get2()
+        .and(warp::path("analytics"))
+        .and(warp::path("metrics"))
+        .and(warp::path::end())
+        .and(with_queue.clone())
+        .map(|queue: Arc<Queue>| {
+            Response::builder()
+                .header("content-type", "text/plain; version=0.0.4")
+                .body(queue.render_metrics())
+        });
+    let paths = warp::get2()
+        .and(warp::path("analytics"))
+        .and(warp::path("paths"))
+        .and(warp::path::param())
+        .and(warp::query())
+        .and(warp::header::headers_cloned())
+        .and(with_pool.clone())
+        .and_then(|window: String, query: ReportQuery, headers: HeaderMap, pool: Pool| {
+            tables_handler(&window, &query, &headers, None, |window, site| paths::report(&pool, window, site, query.compare))
+        })
+        .with(log);
+    let funnels = warp::get2()
+        .and(warp::path("analytics"))
+        .and(warp::path("funnels"))
+        .and(warp::path::param())
+        .and(warp::query())
+        .and(warp::header::headers_cloned())
+        .and(with_pool.clone())
+        .and(with_config.clone())
+        .and_then(|window: String, query: ReportQuery, headers: HeaderMap, pool: Pool, config: Arc<Config>| {
+            tables_handler(&window, &query, &headers, None, |window, site| funnels::report(&pool, &config.funnels, window, site, query.compare))
+        })
+        .with(log);
+    let referrers = warp::get2()
+        .and(warp::path("analytics"))
+        .and(warp::path("referrers"))
         .and(warp::path::param())
-        .map(|window: ReportWindow| reports_handler(window, false))
+        .and(warp::query())
+        .and(warp::header::headers_cloned())
+        .and(with_pool.clone())
+        .and(with_rules.clone())
+        .and_then(|window: String, query: ReportQuery, headers: HeaderMap, pool: Pool, rules: Arc<Rules>| {
+            tables_handler(&window, &query, &headers, None, |window, site| {
+                referrers::report(&pool, &rules, window, site, query.channel.as_deref(), query.source.as_deref(), query.compare)
+            })
+        })
         .with(log);
-    let reporting = reporting_with_email.or(reporting_no_email);
+    let reporting = reporting_with_email.or(metrics).or(paths).or(funnels).or(referrers).or(reporting_no_email);
     let catch_all = warp::any().map(catch_all_handler).with(log);
     
-    let analytics = warp::post2().and(warp::path("analytics")).and(landing.or(exiting));
+    let analytics = warp::post2().and(warp::path("analytics")).and(landing.or(exiting).or(event).or(batch));
     let routes = warp::any()
                     .and(analytics)
                     .or(reporting)
+                    .recover(recover)
                     .or(opts)
                     .or(catch_all)
                     .with(cors);
-    warp::serve(routes)
-        .run(([127, 0, 0, 1], 5555));
+    let (tx, rx) = oneshot::channel();
+    let tx = Mutex::new(Some(tx));
+    ctrlc::set_handler(move || {
+        if let Some(tx) = tx.lock().expect("shutdown lock poisoned").take() {
+            let _ = tx.send(());
+        }
+    }).map_err(|e| Error::Other(format!("Unable to listen for shutdown signals: {}", e)))?;
+    let (_, server) = warp::serve(routes)
+        .bind_with_graceful_shutdown(config.server.bind, rx.map_err(|_| ()));
+    tokio::run(server);
+    info!(target: "analytics:info", "Shutting down, waiting on the exiting queue workers");
+    queue.stop();
+    for worker in workers {
+        if worker.join().is_err() {
+            error!(target: "analytics:error", "An exiting queue worker panicked");
+        }
+    }
+    Ok(())
 }
 
-fn landing_handler(mut info: LandingInfo, remote: String, user_agent: String) -> impl Reply {
-    info!(target: "analytics:info", "/analytics/landing {} {}", remote, info);
-    if let Some(idx) = info.page.find("?") {
-        info.page = info.page[0..idx].to_string();
-    }
-    let index = "/index.html";
-    if info.page.ends_with(index) {
-        info.page = info.page.trim_end_matches(index).to_string();
-    } else if info.page.ends_with("/") {
-        info.page = info.page.trim_end_matches("/").to_string();
-    }
-    let res = match data::add_entry(&info, &remote, &user_agent) {
-        Ok(info) => {
-            info!(target: "analytics:info", "Successfully added entry to database");
-            info
-        },
-        Err(e) => {
-            error!(target: "analytics:error", "Error adding entry to database {}", e);
-            return Response::builder()
-                            .status(500)
-                            .body(format!("error: {}", e))
-        }
+/// Like `warp::body::json` but without checking the content type,
+/// `navigator.sendBeacon` can only send `text/plain` without
+/// triggering a CORS preflight. Bodies over `limit` bytes are
+/// rejected before any of it is read
+fn any_json<T: DeserializeOwned + Send>(limit: u64) -> impl Filter<Extract = (T,), Error = Rejection> + Copy {
+    warp::body::content_length_limit(limit)
+        .and(warp::body::concat())
+        .and_then(|body: FullBody| {
+            serde_json::from_slice(body.bytes()).map_err(|e| {
+                debug!("invalid json body {}", e);
+                Rejection::from(Error::Invalid(400, ErrorBody::new(format!("invalid body: {}", e))))
+            })
+        })
+}
+
+/// Turn errors from the handlers and rejected bodies into JSON
+/// responses, anything else falls through to the catch all route
+fn recover(err: Rejection) -> Result<Response<String>, Rejection> {
+    if let Some(e) = err.find_cause::<Error>() {
+        return error_response(e).map_err(warp::reject::custom);
+    }
+    let msg = match err.status().as_u16() {
+        411 => "content-length is required".to_string(),
+        413 => "the request body is too large".to_string(),
+        400 | 415 => err.cause().map(|c| c.to_string()).unwrap_or_else(|| "bad request".to_string()),
+        _ => return Err(err),
     };
-    match serde_json::to_string(&res) {
-        Ok(body) => {
-            info!(target: "analytics:info", "Successfully converted info to JSON");
-            Response::builder()
-                        .body(body)
-        },
-        Err(e) => {
-            error!(target: "analytics:error", "Error converting info to JSON, {}", e);
-            Response::builder()
-                        .status(500)
-                        .body(format!("error: {}", e))
-        },
+    let e = Error::Invalid(err.status().as_u16(), ErrorBody::new(msg));
+    error_response(&e).map_err(warp::reject::custom)
+}
+
+/// The body of every error response, `id` is also logged along
+/// with the full error so a report can be matched to the logs
+#[derive(Serialize, Debug)]
+struct ErrorResponse {
+    kind: ErrorKind,
+    id: Uuid,
+    #[serde(flatten)]
+    body: ErrorBody,
+}
+
+fn error_response(e: &Error) -> Result<Response<String>, HttpError> {
+    let id = Uuid::new_v4();
+    let status = e.status();
+    if status >= 500 {
+        error!(target: "analytics:error", "[{}] {}: {:?}", id, e, e);
+    } else {
+        info!(target: "analytics:info", "[{}] Rejecting request {}", id, e);
     }
+    let body = ErrorResponse {
+        kind: e.kind(),
+        id,
+        body: e.body(),
+    };
+    Response::builder()
+        .status(status)
+        .header("content-type", "application/json")
+        .header("x-correlation-id", id.to_string())
+        .body(serde_json::to_string(&body).unwrap_or_else(|_| format!("{{\"id\":\"{}\"}}", id)))
 }
 
-fn exiting_handler(info: ExitingInfo) -> impl Reply {
+fn json_reply<T: Serialize>(value: &T) -> Result<Response<String>, Error> {
+    let body = serde_json::to_string(value)
+        .map_err(|e| Error::Other(format!("Error converting to JSON, {}", e)))?;
+    Ok(Response::builder()
+        .header("content-type", "application/json")
+        .body(body)?)
+}
+
+fn landing_handler(mut info: LandingInfo, remote: String, user_agent: String, pool: Pool, config: Arc<Config>) -> Result<Response<String>, Rejection> {
+    info!(target: "analytics:info", "/analytics/landing {} {}", remote, info);
+    // normalizing can lengthen a url by percent-encoding it, so
+    // the stored values are the ones that are checked
+    urls::normalize_landing(&mut info, &config.urls);
+    info.validate().map_err(|e| Error::Invalid(422, e))?;
+    let res = data::add_entry(&pool, &info, &remote, &user_agent)?;
+    info!(target: "analytics:info", "Successfully added entry to database");
+    Ok(json_reply(&res)?)
+}
+
+fn exiting_handler(info: ExitingInfo, queue: Arc<Queue>) -> Result<Response<String>, Rejection> {
     info!(target: "analytics:info", "/analytics/exiting {:}", info);
-    ::std::thread::spawn(move|| {
-        match data::update_entry(&info) {
-            Ok(()) => info!(target: "analytics:info", "Successfully updated entry"),
-            Err(e) => error!(target: "analytics:error", "Error updating entry {}", e),
-        }
-    });
-    warp::reply()
+    info.validate().map_err(|e| Error::Invalid(422, e))?;
+    if !queue.push(info)? {
+        warn!(target: "analytics:warn", "Exiting queue is full, dropping update");
+        return Err(Error::Unavailable("too many pending updates".to_string()).into());
+    }
+    Ok(Response::builder().body(String::new()).map_err(Error::from)?)
+}
+
+fn event_handler(info: EventInfo, pool: Pool) -> Result<Response<String>, Rejection> {
+    info!(target: "analytics:info", "/analytics/event {}", info);
+    info.validate().map_err(|e| Error::Invalid(422, e))?;
+    if !data::add_event(&pool, &info)? {
+        return Err(Error::NotFound(format!("unknown visit {}", info.visit)).into());
+    }
+    Ok(Response::builder().body(String::new()).map_err(Error::from)?)
+}
+
+fn batch_handler(mut items: Vec<BatchItem>, remote: String, user_agent: String, pool: Pool, queue: Arc<Queue>, config: Arc<Config>) -> Result<Response<String>, Rejection> {
+    info!(target: "analytics:info", "/analytics/batch {} {} items", remote, items.len());
+    if items.len() > MAX_BATCH_ITEMS {
+        let msg = format!("batches are limited to {} items", MAX_BATCH_ITEMS);
+        return Err(Error::Invalid(413, ErrorBody::new(msg)).into());
+    }
+    let checks: Vec<Result<(), ErrorBody>> = items.iter_mut().map(|item| match *item {
+        BatchItem::Landing(ref mut info) => {
+            urls::normalize_landing(info, &config.urls);
+            info.validate()
+        },
+        BatchItem::Exiting(ref info) => info.validate(),
+        BatchItem::Event(ref info) => info.validate(),
+    }).collect();
+    let valid: Vec<BatchItem> = items.into_iter()
+        .zip(checks.iter())
+        .filter(|(_, check)| check.is_ok())
+        .map(|(item, _)| item)
+        .collect();
+    let mut applied = data::apply_batch(&pool, &queue, valid, &remote, &user_agent)?.into_iter();
+    let results: Vec<BatchResult> = checks.into_iter().map(|check| match check {
+        Ok(()) => applied.next().unwrap_or_else(|| BatchResult::error(500, "missing result".into())),
+        Err(e) => BatchResult::error(422, e.to_string()),
+    }).collect();
+    Ok(json_reply(&results)?)
 }
 
 fn catch_all_handler() -> impl Reply {
@@ -165,67 +478,80 @@ fn catch_all_handler() -> impl Reply {
         .body("<html><head></head><body><h1>analytics smoketest</h1></body>")
 }
 
-fn reports_handler(window: ReportWindow, email: bool) -> impl Reply {
-    let tables = match data::reports(&window) {
-        Ok(tables) => tables,
-        Err(e) => return Response::builder().status(500).body(format!("{}", e)),
-    };
+fn reports_handler(window: &str, query: &ReportQuery, headers: &HeaderMap, email: bool, pool: &Pool, rules: &Rules, config: &Config) -> Result<Response<String>, Rejection> {
+    let email = if email { Some(config) } else { None };
+    tables_handler(window, query, headers, email, |window, site| data::reports(pool, rules, window, site, query.compare))
+}
+
+/// Parse the window, site and format of a report request and render
+/// the tables built by `build`, when `email` is provided the tables
+/// are also sent to the configured recipients
+fn tables_handler<F>(window: &str, query: &ReportQuery, headers: &HeaderMap, email: Option<&Config>, build: F) -> Result<Response<String>, Rejection>
+where F: FnOnce(&ReportWindow, Option<&str>) -> Result<Vec<Table>, Error>
+{
+    let accept = headers.get("accept").and_then(|h| h.to_str().ok());
+    let format = Format::negotiate(query.format.as_deref(), accept)
+        .map_err(|msg| Error::Invalid(400, ErrorBody::new(msg)))?;
+    let window = ReportWindow::from_request(window, query)
+        .map_err(|msg| Error::Invalid(400, ErrorBody::new(msg)))?;
+    let site = query.site.as_deref().filter(|s| !s.is_empty());
+    let tables = build(&window, site)?;
     debug!("captured db data");
-    let mut reply = reports::generate_ascii_report(&tables);
-    debug!("generated ascii tables");
-    if email {
-        if let Err(msg) = send_email("r.f.masen@gmail.com", tables) {
-            reply = format!("{}\n\n{}", msg, reply);
+    let mut reply = format.render(&tables).map_err(Error::Other)?;
+    debug!("generated {:?} report", format);
+    let mut res = Response::builder();
+    res.header("content-type", format.content_type());
+    if let Some(config) = email {
+        if let Err(msg) = send_email(config, site, tables) {
+            error!(target: "analytics:error", "Error sending report email {}", msg);
+            match format {
+                Format::Ascii |
+                Format::Text => reply = format!("{}\n\n{}", msg, reply),
+                Format::Json |
+                Format::Csv => {
+                    res.header("x-email-error", msg.replace(|c: char| !c.is_ascii() || c.is_ascii_control(), " "));
+                },
+            }
         }
     }
-    Response::builder().header("content-type", "text/plain").body(reply)
+    Ok(res.body(reply).map_err(Error::from)?)
 }
 
-fn send_email(address: &str, tables: Vec<Table>) -> Result<(), String> {
+fn send_email(config: &Config, site: Option<&str>, tables: Vec<Table>) -> Result<(), String> {
     use lettre_email::EmailBuilder;
-    use lettre::{SmtpTransport, Transport, SmtpClient};
-    let msg = reports::generate_report(tables).map_err(|e| format!("{}", e))?;
-    let email = EmailBuilder::new()
-        .from("r@robertmasen.pizza")
-        .to(address)
-        .subject(format!("Weekly analytics report {}", chrono::Local::today()))
-        .html(msg.clone())
-        .build()
+    use lettre::{ClientSecurity, ClientTlsParameters, SmtpTransport, Transport, SmtpClient};
+    use lettre::smtp::authentication::Credentials;
+    use native_tls::TlsConnector;
+    let msg = reports::generate_report(tables)?;
+    let mut builder = EmailBuilder::new()
+        .from(config.reports.from.as_str())
+        .subject(match site {
+            Some(site) => format!("Weekly analytics report for {} {}", site, chrono::Local::today()),
+            None => format!("Weekly analytics report {}", chrono::Local::today()),
+        })
+        .html(msg.clone());
+    for address in &config.reports.recipients {
+        builder = builder.to(address.as_str());
+    }
+    let email = builder.build()
         .map_err(|e| format!("{}", e))?;
-    let client = SmtpClient::new_unencrypted_localhost()
+    let smtp = &config.smtp;
+    let security = if smtp.tls {
+        let connector = TlsConnector::new().map_err(|e| format!("{}", e))?;
+        ClientSecurity::Required(ClientTlsParameters::new(smtp.host.clone(), connector))
+    } else {
+        ClientSecurity::None
+    };
+    let mut client = SmtpClient::new((smtp.host.as_str(), smtp.port), security)
         .map_err(|e| format!("{}", e))?;
+    if let (Some(username), Some(password)) = (&smtp.username, &smtp.password) {
+        client = client.credentials(Credentials::new(username.clone(), password.clone()));
+    }
     let mut mailer = SmtpTransport::new(client);
     mailer.send(email.into()).map_err(|e| format!("{}", e))?;
     Ok(())
 }
 
-enum ReportWindow {
-    Day,
-    Week,
-    Month,
-}
-
-impl ::std::str::FromStr for ReportWindow {
-    type Err = ();
-    fn from_str(s: &str) -> Result<Self, ()> {
-        Ok(match s {
-            "day" => ReportWindow::Day,
-            "month" => ReportWindow::Month,
-            _ => ReportWindow::Week,
-        })
-    }
-}
-
-impl ReportWindow {
-    pub fn to_sql(&self) -> i32 {
-        match self {
-            ReportWindow::Day => 1,
-            ReportWindow::Week => 7,
-            ReportWindow::Month => 30,
-        }
-    }
-}
-
 #[derive(Serialize, Deserialize, Debug)]
 struct LandingInfo {
     referrer: Option<String>,
@@ -233,7 +559,17 @@ struct LandingInfo {
     cookie: Option<Uuid>,
     when: DateTime<Utc>,
     prev_visit: Option<Uuid>,
-    site: Option<String>
+    site: Option<String>,
+    /// Filled in from the page before it is normalized
+    #[serde(skip)]
+    campaign: Campaign,
+    /// The site and thread the referrer points at, filled in
+    /// before it is normalized
+    #[serde(skip)]
+    discussion: Option<(&'static str, String)>,
+    /// Filled in from the referrer before it is normalized
+    #[serde(skip)]
+    search_terms: Option<String>,
 }
 
 impl ::std::fmt::Display for LandingInfo {
@@ -266,6 +602,79 @@ struct ExitingInfo {
     link_clicked: Option<String>,
 }
 
+/// A custom event, like copying a code snippet, that happened
+/// during the visit with the token `visit`
+#[derive(Serialize, Deserialize, Debug)]
+struct EventInfo {
+    name: String,
+    properties: Option<serde_json::Map<String, serde_json::Value>>,
+    visit: Uuid,
+    when: DateTime<Utc>,
+}
+
+impl ::std::fmt::Display for EventInfo {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        write!(f, "{} {} {}", self.visit, self.name, self.when)
+    }
+}
+
+/// The largest body accepted by `/analytics/landing`,
+/// `/analytics/exiting` and `/analytics/event`
+const MAX_BODY_BYTES: u64 = 16 * 1024;
+
+/// The most items accepted by `/analytics/batch` in one request
+const MAX_BATCH_ITEMS: usize = 100;
+
+/// The largest body accepted by `/analytics/batch`, enough for a
+/// full batch of events with the largest allowed properties
+const MAX_BATCH_BYTES: u64 = 512 * 1024;
+
+/// One record queued by a client while it couldn't reach us,
+/// tagged with its `type`
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(tag = "type", rename_all = "lowercase")]
+enum BatchItem {
+    Landing(LandingInfo),
+    Exiting(ExitingInfo),
+    Event(EventInfo),
+}
+
+/// The outcome of a single batch item, `status` is the status
+/// the item would have gotten from its own endpoint
+#[derive(Serialize, Deserialize, Debug)]
+struct BatchResult {
+    status: u16,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    landing: Option<InitialResponse>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+impl BatchResult {
+    fn ok() -> Self {
+        Self {
+            status: 200,
+            landing: None,
+            error: None,
+        }
+    }
+
+    fn landing(res: InitialResponse) -> Self {
+        Self {
+            landing: Some(res),
+            ..Self::ok()
+        }
+    }
+
+    fn error(status: u16, msg: String) -> Self {
+        Self {
+            status,
+            landing: None,
+            error: Some(msg),
+        }
+    }
+}
+
 impl ::std::fmt::Display for ExitingInfo {
     fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
         let link = if let Some(ref link) = self.link_clicked {
@@ -282,14 +691,97 @@ enum Error {
     Other(String),
     Postgres(PError),
     ParseInt(ParseIntError),
+    Pool(PoolError),
+    Tls(TlsError),
+    Http(HttpError),
+    /// The request itself was wrong, along with the 4xx status
+    /// to respond with
+    Invalid(u16, ErrorBody),
+    NotFound(String),
+    Unavailable(String),
+}
+
+/// The categories an `Error` falls into, these decide the status
+/// of the response and how much of the error the client sees
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+enum ErrorKind {
+    Validation,
+    NotFound,
+    Unavailable,
+    Internal,
+}
+
+impl Error {
+    fn kind(&self) -> ErrorKind {
+        match self {
+            Error::Invalid(..) => ErrorKind::Validation,
+            Error::NotFound(_) => ErrorKind::NotFound,
+            Error::Unavailable(_) => ErrorKind::Unavailable,
+            _ if self.is_transient() => ErrorKind::Unavailable,
+            _ => ErrorKind::Internal,
+        }
+    }
+
+    /// The HTTP status code to respond with when this error
+    /// ends a request
+    fn status(&self) -> u16 {
+        match self {
+            Error::Invalid(status, _) => *status,
+            _ => match self.kind() {
+                ErrorKind::Validation => 400,
+                ErrorKind::NotFound => 404,
+                ErrorKind::Unavailable => 503,
+                ErrorKind::Internal => 500,
+            },
+        }
+    }
+
+    /// What a client is allowed to see of this error, anything that
+    /// came from postgres, the pool or the filesystem is replaced
+    /// with a generic message
+    fn body(&self) -> ErrorBody {
+        match self {
+            Error::Invalid(_, body) => body.clone(),
+            Error::NotFound(msg) |
+            Error::Unavailable(msg) => ErrorBody::new(msg.clone()),
+            _ => ErrorBody::new(match self.kind() {
+                ErrorKind::Unavailable => "service temporarily unavailable",
+                _ => "internal server error",
+            }.to_string()),
+        }
+    }
+
+    /// Errors that might go away on their own, the pool timing out,
+    /// I/O errors talking to postgres and the SQLSTATEs for a lost
+    /// connection (class 08), serialization failures, deadlocks and
+    /// an administrator shutting the server down. Anything else,
+    /// like a conversion error, would fail again the same way
+    fn is_transient(&self) -> bool {
+        match self {
+            Error::Pool(_) => true,
+            Error::Postgres(e) if e.as_io().is_some() => true,
+            Error::Postgres(e) => e.code().map(|state| {
+                let code = state.code();
+                code.starts_with("08") || code == "40001" || code == "40P01" || code == "57P01"
+            }).unwrap_or(false),
+            _ => false,
+        }
+    }
 }
 
 impl StdError for Error {
-    fn cause(&self) -> Option<&dyn StdError> {
+    fn source(&self) -> Option<&(dyn StdError + 'static)> {
         match self {
             Error::Other(_) => None,
             Error::Postgres(ref e) => Some(e),
             Error::ParseInt(ref e) => Some(e),
+            Error::Pool(ref e) => Some(e),
+            Error::Tls(ref e) => Some(e),
+            Error::Http(ref e) => Some(e),
+            Error::Invalid(..) |
+            Error::NotFound(_) |
+            Error::Unavailable(_) => None,
         }
     }
 }
@@ -300,7 +792,10 @@ impl ::std::fmt::Display for Error {
             inner.fmt(f)
         } else {
             match self {
-                Error::Other(s) => s.fmt(f),
+                Error::Other(s) |
+                Error::NotFound(s) |
+                Error::Unavailable(s) => s.fmt(f),
+                Error::Invalid(_, body) => body.fmt(f),
                 _ => unreachable!()
             }
         }
@@ -313,32 +808,71 @@ impl From<PError> for Error {
     }
 }
 
+impl From<TlsError> for Error {
+    fn from(other: TlsError) -> Self {
+        Error::Tls(other)
+    }
+}
+
 impl From<ParseIntError> for Error {
     fn from(other: ParseIntError) -> Self {
         Error::ParseInt(other)
     }
 }
 
+impl From<HttpError> for Error {
+    fn from(other: HttpError) -> Self {
+        Error::Http(other)
+    }
+}
+
+impl From<Error> for Rejection {
+    fn from(other: Error) -> Self {
+        warp::reject::custom(other)
+    }
+}
+
 #[cfg(test)]
 mod test {
     use reqwest;
     use chrono::Utc;
-    use super::{LandingInfo, ExitingInfo, InitialResponse, main};
+    use super::{BatchItem, BatchResult, Error, ErrorKind, LandingInfo, ExitingInfo, EventInfo, InitialResponse, run};
+    use config::Config;
+    use validation::ErrorBody;
+
+    #[test]
+    fn error_kinds() {
+        let invalid = Error::Invalid(422, ErrorBody::new("invalid request body".into()));
+        assert_eq!(invalid.kind(), ErrorKind::Validation);
+        assert_eq!(invalid.status(), 422);
+        assert_eq!(invalid.body(), ErrorBody::new("invalid request body".into()));
+        let missing = Error::NotFound("unknown visit".into());
+        assert_eq!(missing.status(), 404);
+        assert_eq!(missing.body().error, "unknown visit");
+        let full = Error::Unavailable("too many pending updates".into());
+        assert_eq!(full.kind(), ErrorKind::Unavailable);
+        assert_eq!(full.status(), 503);
+        let other = Error::Other("relation \"session\" does not exist".into());
+        assert_eq!(other.kind(), ErrorKind::Internal);
+        assert_eq!(other.status(), 500);
+        assert_eq!(other.body().error, "internal server error");
+    }
+
     #[test]
     fn test_server() -> Result<(), reqwest::Error> {
+        use std::{io::{Read, Write}, net::TcpStream};
         debug!(target: "analytics:test", "starting test_server");
-        ::std::thread::spawn(|| main());
+        let config = Config::load(None).expect("Unable to load config");
+        {
+            let pool = ::data::create_pool(&config.db).expect("Unable to create pool");
+            ::migrations::up(&pool.get().expect("Unable to connect")).expect("Unable to migrate");
+        }
+        let bind = config.server.bind;
+        let addr = format!("http://{}/analytics", bind);
+        ::std::thread::spawn(move || run(config));
         ::std::thread::sleep(::std::time::Duration::from_secs(2));
         let c = reqwest::Client::new();
-        let addr = "http://localhost:5555/analytics";
-        let first_body = LandingInfo {
-            referrer: None,
-            page: String::from("http://example.com"),
-            cookie: None,
-            when: Utc::now(),
-            prev_visit: None,
-            site: Some("example.com".into()),
-        };
+        let first_body = ::test_support::landing("example.com", "http://example.com");
         let res: InitialResponse = c.post(&format!("{}/landing", addr))
                                                 .header("x-client-address", "0.0.0.0")
                                                 .json(&first_body)
@@ -353,8 +887,120 @@ mod test {
         c.post(&format!("{}/exiting", addr))
                                 .json(&second_body)
                                 .send()?;
+        // navigator.sendBeacon sends strings as text/plain
+        let beacon = c.post(&format!("{}/exiting", addr))
+                                .header("content-type", "text/plain;charset=UTF-8")
+                                .body(::serde_json::to_string(&second_body).unwrap())
+                                .send()?;
+        assert!(beacon.status().is_success());
+        let invalid = c.post(&format!("{}/exiting", addr))
+                                .header("content-type", "text/plain")
+                                .body("{\"visit\": ")
+                                .send()?;
+        assert_eq!(invalid.status().as_u16(), 400);
+        // the body is never read so the server closes the connection,
+        // a client of its own keeps that from reaching `c`'s pool
+        let huge = reqwest::Client::new().post(&format!("{}/landing", addr))
+                                .header("x-client-address", "0.0.0.0")
+                                .body(vec![b' '; 32 * 1024])
+                                .send()?;
+        assert_eq!(huge.status().as_u16(), 413);
+        let event = EventInfo {
+            name: "played video".into(),
+            properties: None,
+            visit: res.visit,
+            when: Utc::now(),
+        };
+        let status = c.post(&format!("{}/event", addr))
+                                .json(&event)
+                                .send()?
+                                .status();
+        assert!(status.is_success());
+        let batch = vec![
+            BatchItem::Exiting(ExitingInfo {
+                visit: res.visit,
+                time: 2000,
+                link_clicked: None,
+            }),
+            BatchItem::Event(EventInfo {
+                name: "queued".into(),
+                properties: None,
+                visit: res.visit,
+                when: Utc::now(),
+            }),
+        ];
+        let mut invalid_page = c.post(&format!("{}/landing", addr))
+                                .header("x-client-address", "0.0.0.0")
+                                .json(&LandingInfo {
+                                    page: String::new(),
+                                    ..first_body
+                                })
+                                .send()?;
+        assert_eq!(invalid_page.status().as_u16(), 422);
+        let body: ::serde_json::Value = ::serde_json::from_str(&invalid_page.text()?).unwrap();
+        assert_eq!(body["fields"][0]["field"], "page");
+        assert_eq!(body["kind"], "validation");
+        // short enough as sent but not once it is percent-encoded
+        let encoded = || ::test_support::landing("example.com", &format!("https://example.com/{}", "é".repeat(100)));
+        let mut too_long = c.post(&format!("{}/landing", addr))
+                                .header("x-client-address", "0.0.0.0")
+                                .json(&encoded())
+                                .send()?;
+        assert_eq!(too_long.status().as_u16(), 422);
+        let body: ::serde_json::Value = ::serde_json::from_str(&too_long.text()?).unwrap();
+        assert_eq!(body["fields"][0]["field"], "page");
+        let results: Vec<BatchResult> = c.post(&format!("{}/batch", addr))
+                                .header("x-client-address", "0.0.0.0")
+                                .json(&vec![BatchItem::Landing(encoded())])
+                                .send()?
+                                .json()?;
+        assert_eq!(results[0].status, 422);
+        let mut unknown = c.post(&format!("{}/event", addr))
+                                .json(&EventInfo {
+                                    visit: ::uuid::Uuid::new_v4(),
+                                    ..event
+                                })
+                                .send()?;
+        assert_eq!(unknown.status().as_u16(), 404);
+        let id = unknown.headers().get("x-correlation-id").map(|h| h.to_str().unwrap().to_string());
+        let body: ::serde_json::Value = ::serde_json::from_str(&unknown.text()?).unwrap();
+        assert_eq!(body["kind"], "not_found");
+        assert_eq!(body["id"].as_str().map(String::from), id);
+        let results: Vec<BatchResult> = c.post(&format!("{}/batch", addr))
+                                .header("x-client-address", "0.0.0.0")
+                                .json(&batch)
+                                .send()?
+                                .json()?;
+        assert!(results.iter().all(|r| r.status == 200));
+        let results: Vec<BatchResult> = c.post(&format!("{}/batch", addr))
+                                .header("x-client-address", "0.0.0.0")
+                                .header("content-type", "text/plain;charset=UTF-8")
+                                .body(::serde_json::to_string(&batch).unwrap())
+                                .send()?
+                                .json()?;
+        assert_eq!(results.len(), 2);
+        // only the headers are sent, the server answers before the
+        // body would have been read and a client writing it would
+        // have the connection closed on it
+        let mut huge = TcpStream::connect(bind).unwrap();
+        write!(huge, "POST /analytics/batch HTTP/1.1\r\nhost: {}\r\nx-client-address: 0.0.0.0\r\ncontent-length: {}\r\n\r\n", bind, 1024 * 1024).unwrap();
+        let mut status = [0; 12];
+        huge.read_exact(&mut status).unwrap();
+        assert_eq!(&status, b"HTTP/1.1 413");
         debug!(target: "analytics:test",  "finishing test_server");
         ::std::thread::sleep(::std::time::Duration::from_secs(2));
         Ok(())
     }
+
+    #[test]
+    fn transient_errors() {
+        use std::io;
+        use postgres::error::conversion;
+        use super::Error;
+        let lost = io::Error::new(io::ErrorKind::ConnectionReset, "connection reset");
+        assert!(Error::Postgres(lost.into()).is_transient());
+        let bad_type = conversion("cannot convert between the Rust type `String` and the Postgres type `int4`".into());
+        assert!(!Error::Postgres(bad_type).is_transient());
+        assert!(!Error::Other("not a database error".into()).is_transient());
+    }
 }
\ No newline at end of file
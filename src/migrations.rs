@@ -0,0 +1,311 @@
+use chrono::NaiveDateTime;
+use clap::{App, Arg, ArgMatches, SubCommand};
+use postgres::{Connection, GenericConnection};
+
+use super::Error;
+
+/// An arbitrary key for `pg_advisory_xact_lock` so two
+/// instances never migrate the same database at once
+const LOCK_KEY: i64 = 0x616e_616c_7974;
+
+/// A single schema version, the SQL for both directions is
+/// embedded in the binary and each direction is run inside
+/// of one transaction
+pub(crate) struct Migration {
+    pub name: &'static str,
+    up: &'static [&'static str],
+    down: &'static [&'static str],
+}
+
+static MIGRATIONS: &[Migration] = &[
+    Migration {
+        name: "00_init",
+        up: &[
+            include_str!("../migrations/00/tables.sql"),
+            include_str!("../migrations/00/types.sql"),
+            include_str!("../migrations/00/roles.sql"),
+            include_str!("../migrations/00/add_session.sql"),
+            include_str!("../migrations/00/update_session.sql"),
+            include_str!("../migrations/00/permissions.sql"),
+        ],
+        down: &[
+            include_str!("../migrations/00/add_session.down.sql"),
+            include_str!("../migrations/00/update_session.down.sql"),
+            include_str!("../migrations/00/types.down.sql"),
+            include_str!("../migrations/00/tables.down.sql"),
+            include_str!("../migrations/00/permissions.down.sql"),
+            include_str!("../migrations/00/roles.down.sql"),
+        ],
+    },
+    Migration {
+        name: "01_site_user_agent",
+        up: &[
+            include_str!("../migrations/01/up.sql"),
+            include_str!("../migrations/01/sessions.sql"),
+        ],
+        down: &[include_str!("../migrations/01/down.sql")],
+    },
+    Migration {
+        name: "02_weekly_reports",
+        up: &[include_str!("../migrations/02/up.sql")],
+        down: &[include_str!("../migrations/02/down.sql")],
+    },
+    Migration {
+        name: "03_windowed_reports",
+        up: &[include_str!("../migrations/03/up.sql")],
+        down: &[include_str!("../migrations/03/down.sql")],
+    },
+    Migration {
+        name: "04_ranged_reports",
+        up: &[include_str!("../migrations/04/up.sql")],
+        // restores the functions 04 replaced
+        down: &[
+            include_str!("../migrations/04/down.sql"),
+            include_str!("../migrations/03/up.sql"),
+        ],
+    },
+    Migration {
+        name: "05_site_reports",
+        up: &[include_str!("../migrations/05/up.sql")],
+        // restores the functions 05 replaced
+        down: &[
+            include_str!("../migrations/05/down.sql"),
+            include_str!("../migrations/04/up.sql"),
+        ],
+    },
+    Migration {
+        name: "06_engagement",
+        up: &[include_str!("../migrations/06/up.sql")],
+        down: &[include_str!("../migrations/06/down.sql")],
+    },
+    Migration {
+        name: "07_paths",
+        up: &[include_str!("../migrations/07/up.sql")],
+        down: &[include_str!("../migrations/07/down.sql")],
+    },
+    Migration {
+        name: "08_cohorts",
+        up: &[include_str!("../migrations/08/up.sql")],
+        down: &[include_str!("../migrations/08/down.sql")],
+    },
+    Migration {
+        name: "09_events",
+        up: &[include_str!("../migrations/09/up.sql")],
+        down: &[include_str!("../migrations/09/down.sql")],
+    },
+    Migration {
+        name: "10_campaigns",
+        up: &[include_str!("../migrations/10/up.sql")],
+        // restores the session functions 10 replaced
+        down: &[
+            include_str!("../migrations/10/down.sql"),
+            include_str!("../migrations/01/sessions.sql"),
+        ],
+    },
+    Migration {
+        name: "11_referrer_channels",
+        up: &[include_str!("../migrations/11/up.sql")],
+        down: &[include_str!("../migrations/11/down.sql")],
+    },
+    Migration {
+        name: "12_referrer_details",
+        up: &[include_str!("../migrations/12/up.sql")],
+        // restores the referrer_sessions from 11
+        down: &[
+            include_str!("../migrations/12/down.sql"),
+            include_str!("../migrations/11/up.sql"),
+        ],
+    },
+    Migration {
+        name: "13_user_agent_families",
+        up: &[include_str!("../migrations/13/up.sql")],
+        down: &[include_str!("../migrations/13/down.sql")],
+    },
+];
+
+/// What the `migrate` sub command was asked to do
+#[derive(Debug, PartialEq)]
+pub enum Command {
+    Up,
+    Down,
+    Status,
+    /// Record every migration up to and including this one as
+    /// applied without running it, for databases that were set
+    /// up by hand before the `migration` table was used
+    Baseline(String),
+}
+
+impl Command {
+    pub fn from_matches(matches: &ArgMatches) -> Self {
+        match matches.subcommand() {
+            ("up", _) => Command::Up,
+            ("down", _) => Command::Down,
+            ("baseline", Some(m)) => Command::Baseline(m.value_of("name").unwrap_or_default().to_string()),
+            _ => Command::Status,
+        }
+    }
+}
+
+pub(crate) fn subcommand() -> App<'static, 'static> {
+    SubCommand::with_name("migrate")
+        .about("Manage the database schema")
+        .subcommand(SubCommand::with_name("up")
+            .about("Apply every pending migration"))
+        .subcommand(SubCommand::with_name("down")
+            .about("Revert the most recently applied migration"))
+        .subcommand(SubCommand::with_name("status")
+            .about("List each migration and when it was applied"))
+        .subcommand(SubCommand::with_name("baseline")
+            .about("Mark migrations as applied without running them")
+            .arg(Arg::with_name("name")
+                .required(true)
+                .help("The last migration already present in the database")))
+}
+
+/// Apply every migration that hasn't been applied yet, returning
+/// the names of those that ran
+pub(crate) fn up(conn: &Connection) -> Result<Vec<&'static str>, Error> {
+    let mut ret = Vec::new();
+    for migration in pending(conn)? {
+        info!(target: "analytics:info", "applying migration {}", migration.name);
+        let trans = conn.transaction()?;
+        trans.execute("SELECT pg_advisory_xact_lock($1)", &[&LOCK_KEY])?;
+        if is_applied(&trans, migration.name)? {
+            continue;
+        }
+        for sql in migration.up {
+            trans.batch_execute(sql)?;
+        }
+        trans.execute("INSERT INTO migration (name) VALUES ($1)", &[&migration.name])?;
+        trans.commit()?;
+        ret.push(migration.name);
+    }
+    Ok(ret)
+}
+
+/// Revert the most recently applied migration, returning its name
+pub(crate) fn down(conn: &Connection) -> Result<Option<&'static str>, Error> {
+    let migration = match applied(conn)?.last() {
+        Some(&(name, _)) => find(name)?,
+        None => return Ok(None),
+    };
+    info!(target: "analytics:info", "reverting migration {}", migration.name);
+    let trans = conn.transaction()?;
+    trans.execute("SELECT pg_advisory_xact_lock($1)", &[&LOCK_KEY])?;
+    if !is_applied(&trans, migration.name)? {
+        return Ok(None);
+    }
+    trans.execute("DELETE FROM migration WHERE name = $1", &[&migration.name])?;
+    for sql in migration.down {
+        trans.batch_execute(sql)?;
+    }
+    trans.commit()?;
+    Ok(Some(migration.name))
+}
+
+/// Every known migration paired with when it was applied
+pub(crate) fn status(conn: &Connection) -> Result<Vec<(&'static str, Option<NaiveDateTime>)>, Error> {
+    let applied = applied(conn)?;
+    Ok(MIGRATIONS.iter().map(|m| {
+        let when = applied.iter()
+            .find(|&&(name, _)| name == m.name)
+            .and_then(|&(_, when)| when);
+        (m.name, when)
+    }).collect())
+}
+
+/// The migrations that still need to be applied, in order
+pub(crate) fn pending(conn: &Connection) -> Result<Vec<&'static Migration>, Error> {
+    let applied = applied(conn)?;
+    Ok(MIGRATIONS.iter()
+        .filter(|m| !applied.iter().any(|&(name, _)| name == m.name))
+        .collect())
+}
+
+pub(crate) fn baseline(conn: &Connection, last: &str) -> Result<Vec<&'static str>, Error> {
+    find(last)?;
+    if !table_exists(conn)? {
+        return Err(Error::Other("The migration table doesn't exist, nothing to baseline".into()));
+    }
+    let trans = conn.transaction()?;
+    trans.execute("SELECT pg_advisory_xact_lock($1)", &[&LOCK_KEY])?;
+    let mut ret = Vec::new();
+    for migration in MIGRATIONS {
+        if !is_applied(&trans, migration.name)? {
+            trans.execute("INSERT INTO migration (name) VALUES ($1)", &[&migration.name])?;
+            ret.push(migration.name);
+        }
+        if migration.name == last {
+            break;
+        }
+    }
+    trans.commit()?;
+    Ok(ret)
+}
+
+fn find(name: &str) -> Result<&'static Migration, Error> {
+    MIGRATIONS.iter()
+        .find(|m| m.name == name)
+        .ok_or_else(|| Error::Other(format!("Unknown migration {}", name)))
+}
+
+/// The names of the applied migrations that this binary knows about,
+/// in the order they are defined
+fn applied(conn: &Connection) -> Result<Vec<(&'static str, Option<NaiveDateTime>)>, Error> {
+    if !table_exists(conn)? {
+        return Ok(Vec::new());
+    }
+    let rows = conn.query("SELECT name, applied FROM migration", &[])?;
+    let mut ret = Vec::new();
+    for migration in MIGRATIONS {
+        if let Some(row) = rows.iter().find(|r| r.get::<_, String>(0) == migration.name) {
+            ret.push((migration.name, row.get(1)));
+        }
+    }
+    Ok(ret)
+}
+
+fn is_applied(conn: &dyn GenericConnection, name: &str) -> Result<bool, Error> {
+    if !table_exists(conn)? {
+        return Ok(false);
+    }
+    let rows = conn.query("SELECT 1 FROM migration WHERE name = $1", &[&name])?;
+    Ok(!rows.is_empty())
+}
+
+fn table_exists(conn: &dyn GenericConnection) -> Result<bool, Error> {
+    let rows = conn.query("SELECT to_regclass('migration') IS NOT NULL", &[])?;
+    Ok(rows.get(0).get(0))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn names_are_ordered() {
+        let names: Vec<&str> = MIGRATIONS.iter().map(|m| m.name).collect();
+        let mut sorted = names.clone();
+        sorted.sort();
+        sorted.dedup();
+        assert_eq!(names, sorted);
+    }
+
+    #[test]
+    fn up_to_date() {
+        let config = ::config::Config::load(None).expect("Unable to load config");
+        let pool = ::data::create_pool(&config.db).expect("Unable to create pool");
+        let conn = pool.get().expect("Unable to connect");
+        up(&conn).unwrap();
+        assert!(pending(&conn).unwrap().is_empty());
+        assert!(status(&conn).unwrap().iter().all(|&(_, when)| when.is_some()));
+    }
+
+    #[test]
+    fn parse_command() {
+        let app = App::new("test").subcommand(subcommand());
+        let matches = app.get_matches_from(vec!["test", "migrate", "baseline", "01_site_user_agent"]);
+        let cmd = Command::from_matches(matches.subcommand_matches("migrate").unwrap());
+        assert_eq!(cmd, Command::Baseline("01_site_user_agent".to_string()));
+    }
+}
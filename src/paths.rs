@@ -0,0 +1,227 @@
+use std::collections::{HashMap, HashSet};
+
+use postgres::Connection;
+use uuid::Uuid;
+
+use data::{add_previous, count_table, get_connection, not_compared, Pool};
+use reports::{Cell, Column, Kind, Table};
+use window::ReportWindow;
+use super::Error;
+
+/// How many of the most common paths are reported
+const MAX_PATHS: usize = 25;
+/// Paths are joined with this for display
+const SEPARATOR: &str = " → ";
+
+/// One row of the `path_sessions` function
+#[derive(Debug)]
+struct Session {
+    cookie: i32,
+    visit: Uuid,
+    prev: Option<Uuid>,
+    page: String,
+}
+
+/// The sessions reached by following `prev_visit_token` from a
+/// single entry session. Opening a link in a new tab gives a
+/// session more than one follow up, so a visit can have more
+/// than one path and exit page
+#[derive(Debug, PartialEq)]
+struct Visit<'a> {
+    entry: &'a str,
+    paths: Vec<Vec<&'a str>>,
+    pages: usize,
+}
+
+/// Build the path analysis tables for `window`, sessions that
+/// continue a visit started before the window are left out. With
+/// `compare` the path, entry and exit counts are paired with the
+/// previous period's.
+pub(crate) fn report(pool: &Pool, window: &ReportWindow, site: Option<&str>, compare: bool) -> Result<Vec<Table>, Error> {
+    let conn = get_connection(pool)?;
+    let scope = window.scope(site);
+    let (mut ret, average) = tables(&conn, window, site, &scope)?;
+    if compare {
+        let previous = window.previous().map_err(Error::Other)?;
+        let (counts, _) = tables(&conn, &previous, site, &scope)?;
+        ret = ret.into_iter()
+            .zip(counts)
+            .map(|(current, previous)| add_previous(current, previous, 1))
+            .collect();
+        ret.push(not_compared(Some(&previous), average));
+    } else {
+        ret.push(average);
+    }
+    Ok(ret)
+}
+
+/// The common paths, entry pages and exit pages count tables
+/// along with the average pages per visit
+fn tables(conn: &Connection, window: &ReportWindow, site: Option<&str>, scope: &str) -> Result<(Vec<Table>, Table), Error> {
+    let sessions = sessions(conn, window, site)?;
+    let visits = visits(&sessions);
+    let mut paths = HashMap::new();
+    let mut entries = HashMap::new();
+    let mut exits = HashMap::new();
+    for visit in visits.iter() {
+        *entries.entry(visit.entry.to_string()).or_insert(0) += 1;
+        for path in visit.paths.iter() {
+            *paths.entry(path.join(SEPARATOR)).or_insert(0) += 1;
+            if let Some(exit) = path.last() {
+                *exits.entry(exit.to_string()).or_insert(0) += 1;
+            }
+        }
+    }
+    let mut paths = ranked(paths);
+    paths.truncate(MAX_PATHS);
+    let pages: usize = visits.iter().map(|v| v.pages).sum();
+    let mut average = Table::new(format!("{} Pages per Visit", scope), vec![
+        Column::new("Visits", Kind::Int),
+        Column::new("Pages", Kind::Int),
+        Column::new("Average", Kind::Decimal).unit("pages"),
+    ]);
+    average.rows.push(vec![
+        Cell::Int(visits.len() as i64),
+        Cell::Int(pages as i64),
+        if visits.is_empty() {
+            Cell::Null
+        } else {
+            Cell::Decimal(pages as f64 / visits.len() as f64)
+        },
+    ]);
+    Ok((vec![
+        count_table(format!("{} Common Paths", scope), Column::new("Path", Kind::Text), Column::new("Count", Kind::Int), paths),
+        count_table(format!("{} Entry Pages", scope), Column::new("Page", Kind::Url), Column::new("Count", Kind::Int).unit("entries"), ranked(entries)),
+        count_table(format!("{} Exit Pages", scope), Column::new("Page", Kind::Url), Column::new("Count", Kind::Int).unit("exits"), ranked(exits)),
+    ], average))
+}
+
+fn sessions(conn: &Connection, window: &ReportWindow, site: Option<&str>) -> Result<Vec<Session>, Error> {
+    Ok(conn.query("SELECT *
+                FROM path_sessions($1, $2, $3)",
+                &[&window.from, &window.to, &site])?
+        .iter()
+        .map(|r| Session {
+            cookie: r.get(0),
+            visit: r.get(1),
+            prev: r.get(2),
+            page: r.get(3),
+        })
+        .collect())
+}
+
+/// Walk the `prev_visit_token` chains of `sessions`, a session
+/// only continues a visit when the previous session belongs to
+/// the same cookie
+fn visits(sessions: &[Session]) -> Vec<Visit<'_>> {
+    let by_token: HashMap<Uuid, &Session> = sessions.iter().map(|s| (s.visit, s)).collect();
+    let mut children: HashMap<Uuid, Vec<&Session>> = HashMap::new();
+    let mut entries = Vec::new();
+    for session in sessions {
+        match session.prev.and_then(|prev| by_token.get(&prev)) {
+            Some(prev) if prev.cookie == session.cookie => {
+                children.entry(prev.visit).or_default().push(session);
+            },
+            _ => entries.push(session),
+        }
+    }
+    entries.into_iter().map(|entry| {
+        let mut seen = HashSet::new();
+        let mut paths = Vec::new();
+        let mut stack = vec![(entry, vec![entry.page.as_str()])];
+        while let Some((session, path)) = stack.pop() {
+            if !seen.insert(session.visit) {
+                continue;
+            }
+            match children.get(&session.visit) {
+                Some(next) => {
+                    for child in next.iter().rev() {
+                        let mut path = path.clone();
+                        path.push(child.page.as_str());
+                        stack.push((child, path));
+                    }
+                },
+                None => paths.push(path),
+            }
+        }
+        Visit {
+            entry: entry.page.as_str(),
+            paths,
+            pages: seen.len(),
+        }
+    }).collect()
+}
+
+/// Most common first, ties are broken alphabetically
+fn ranked(counts: HashMap<String, i64>) -> Vec<(String, i64)> {
+    let mut ret: Vec<(String, i64)> = counts.into_iter().collect();
+    ret.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+    ret
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn session(cookie: i32, prev: Option<&Session>, page: &str) -> Session {
+        Session {
+            cookie,
+            visit: Uuid::new_v4(),
+            prev: prev.map(|p| p.visit),
+            page: page.to_string(),
+        }
+    }
+
+    #[test]
+    fn chains() {
+        let a = session(1, None, "/");
+        let b = session(1, Some(&a), "/blog");
+        let c = session(1, Some(&b), "/blog/post");
+        // opened in a new tab from the blog index
+        let d = session(1, Some(&b), "/projects");
+        // a token from another cookie starts a new visit
+        let e = session(2, Some(&a), "/about");
+        let sessions = vec![a, b, c, d, e];
+        let visits = visits(&sessions);
+        assert_eq!(visits, vec![
+            Visit {
+                entry: "/",
+                paths: vec![vec!["/", "/blog", "/blog/post"], vec!["/", "/blog", "/projects"]],
+                pages: 4,
+            },
+            Visit {
+                entry: "/about",
+                paths: vec![vec!["/about"]],
+                pages: 1,
+            },
+        ]);
+    }
+
+    #[test]
+    fn report() {
+        use reports::table;
+        use window::ReportQuery;
+        let config = ::config::Config::load(None).expect("Unable to load config");
+        let pool = ::data::create_pool(&config.db).expect("Unable to create pool");
+        ::migrations::up(&pool.get().expect("Unable to connect")).expect("Unable to migrate");
+        let site = format!("{}.example.com", Uuid::new_v4().simple());
+        let mut prev = None;
+        for page in &["/", "/blog", "/blog/post"] {
+            let landing = ::LandingInfo {
+                prev_visit: prev,
+                ..::test_support::landing(&site, &format!("https://{}{}", site, page))
+            };
+            prev = Some(::data::add_entry(&pool, &landing, "5.5.5.5", "I'm a teapot").unwrap().visit);
+        }
+        let window = ReportWindow::from_request("day", &ReportQuery::default()).unwrap();
+        let tables = super::report(&pool, &window, Some(&site), false).unwrap();
+        let path = format!("https://{0}/ → https://{0}/blog → https://{0}/blog/post", site);
+        assert_eq!(table(&tables, "Common Paths").rows, vec![vec![Cell::Text(path.clone()), Cell::Int(1)]]);
+        assert_eq!(table(&tables, "Entry Pages").rows, vec![vec![Cell::Url(format!("https://{}/", site)), Cell::Int(1)]]);
+        assert_eq!(table(&tables, "Exit Pages").rows, vec![vec![Cell::Url(format!("https://{}/blog/post", site)), Cell::Int(1)]]);
+        assert_eq!(table(&tables, "Pages per Visit").rows, vec![vec![Cell::Int(1), Cell::Int(3), Cell::Decimal(3.0)]]);
+        let tables = super::report(&pool, &window, Some(&site), true).unwrap();
+        assert_eq!(table(&tables, "Common Paths").rows, vec![vec![Cell::Text(path), Cell::Int(1), Cell::Int(0), Cell::Int(1), Cell::Null]]);
+        assert!(table(&tables, "Pages per Visit").note.is_some());
+    }
+}
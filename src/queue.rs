@@ -0,0 +1,419 @@
+use std::{
+    collections::VecDeque,
+    fs::{self, File},
+    io::Write,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Condvar, Mutex,
+    },
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+
+use config::QueueConfig;
+use data::{self, Pool};
+use super::{Error, ExitingInfo};
+
+/// The first retry waits this long, each following retry of the
+/// same item waits twice as long up to `QueueConfig::max_backoff`
+const INITIAL_BACKOFF: Duration = Duration::from_millis(500);
+
+/// A bounded queue of exiting updates waiting to be written to
+/// postgres. Every item is written to its own file in `dir` before
+/// it is accepted and removed once the update has been applied, so
+/// anything still queued when the server stops is picked back up
+/// by `Queue::open`.
+pub(crate) struct Queue {
+    dir: PathBuf,
+    capacity: usize,
+    max_backoff: Duration,
+    max_attempts: u32,
+    state: Mutex<State>,
+    ready: Condvar,
+    pub metrics: Metrics,
+}
+
+struct State {
+    pending: VecDeque<(u64, ExitingInfo)>,
+    /// Items taken by a worker that haven't been applied yet
+    in_flight: usize,
+    /// Items given a sequence number that are still being written
+    reserved: usize,
+    next: u64,
+    /// Set by `Queue::stop`, workers exit instead of taking or
+    /// retrying another item
+    stopped: bool,
+}
+
+#[derive(Default)]
+pub(crate) struct Metrics {
+    pub processed: AtomicUsize,
+    /// Rejected because the queue was full or couldn't be written
+    pub dropped: AtomicUsize,
+    /// Discarded after an error that retrying won't fix or after
+    /// failing `QueueConfig::max_attempts` times
+    pub failed: AtomicUsize,
+    pub retries: AtomicUsize,
+}
+
+impl Queue {
+    /// Create `config.path` if needed and load any items left in it
+    pub fn open(config: &QueueConfig) -> Result<Self, Error> {
+        fs::create_dir_all(&config.path)
+            .map_err(|e| io_error("create", &config.path, e))?;
+        let mut entries = Vec::new();
+        for entry in fs::read_dir(&config.path).map_err(|e| io_error("read", &config.path, e))? {
+            let path = entry.map_err(|e| io_error("read", &config.path, e))?.path();
+            match sequence(&path) {
+                Some(seq) => entries.push((seq, path)),
+                // a write that never finished, it was never acknowledged
+                None if path.extension().map(|e| e == "tmp").unwrap_or(false) => {
+                    let _ = fs::remove_file(&path);
+                },
+                None => (),
+            }
+        }
+        entries.sort();
+        let metrics = Metrics::default();
+        let mut pending = VecDeque::new();
+        for (seq, path) in entries.iter() {
+            match fs::read(path).map_err(|e| e.to_string())
+                .and_then(|bytes| ::serde_json::from_slice(&bytes).map_err(|e| e.to_string())) {
+                Ok(info) => pending.push_back((*seq, info)),
+                Err(e) => {
+                    error!(target: "analytics:error", "Discarding unreadable queue item {}: {}", path.display(), e);
+                    metrics.failed.fetch_add(1, Ordering::Relaxed);
+                    let _ = fs::remove_file(path);
+                },
+            }
+        }
+        if !pending.is_empty() {
+            info!(target: "analytics:info", "Recovered {} queued exiting updates", pending.len());
+        }
+        let next = entries.last().map(|&(seq, _)| seq + 1).unwrap_or(0);
+        Ok(Self {
+            dir: config.path.clone(),
+            capacity: config.capacity,
+            max_backoff: Duration::from_secs(config.max_backoff),
+            max_attempts: config.max_attempts,
+            state: Mutex::new(State {
+                pending,
+                in_flight: 0,
+                reserved: 0,
+                next,
+                stopped: false,
+            }),
+            ready: Condvar::new(),
+            metrics,
+        })
+    }
+
+    /// Persist `info` and hand it to the workers, returns false
+    /// when the queue is full and the update was dropped. The file
+    /// is written without holding the lock so other requests and
+    /// the workers aren't kept waiting on the disk
+    pub fn push(&self, info: ExitingInfo) -> Result<bool, Error> {
+        let seq = {
+            let mut state = self.state.lock().expect("queue lock poisoned");
+            if state.pending.len() + state.in_flight + state.reserved >= self.capacity {
+                self.metrics.dropped.fetch_add(1, Ordering::Relaxed);
+                return Ok(false);
+            }
+            state.reserved += 1;
+            state.next += 1;
+            state.next - 1
+        };
+        let written = self.write(seq, &info);
+        let mut state = self.state.lock().expect("queue lock poisoned");
+        state.reserved -= 1;
+        if let Err(e) = written {
+            self.metrics.dropped.fetch_add(1, Ordering::Relaxed);
+            return Err(e);
+        }
+        state.pending.push_back((seq, info));
+        self.ready.notify_one();
+        Ok(true)
+    }
+
+    /// Items waiting to be applied, including those being retried
+    pub fn depth(&self) -> usize {
+        let state = self.state.lock().expect("queue lock poisoned");
+        state.pending.len() + state.in_flight
+    }
+
+    /// Spawn `workers` threads that apply queued updates until the
+    /// process exits or `Queue::stop` is called
+    pub fn start(queue: &Arc<Self>, pool: &Pool, workers: usize) -> Vec<JoinHandle<()>> {
+        (0..workers).map(|i| {
+            let queue = queue.clone();
+            let pool = pool.clone();
+            thread::Builder::new()
+                .name(format!("exiting-{}", i))
+                .spawn(move || queue.work(&pool))
+                .expect("Unable to spawn queue worker")
+        }).collect()
+    }
+
+    /// Let the workers finish the attempt they are on and exit, an
+    /// update that still hasn't been applied stays on disk for the
+    /// next `Queue::open`
+    pub fn stop(&self) {
+        self.state.lock().expect("queue lock poisoned").stopped = true;
+        self.ready.notify_all();
+    }
+
+    fn stopped(&self) -> bool {
+        self.state.lock().expect("queue lock poisoned").stopped
+    }
+
+    fn work(&self, pool: &Pool) {
+        while let Some((seq, info)) = self.take() {
+            let mut backoff = INITIAL_BACKOFF;
+            let mut attempts = 1;
+            loop {
+                match data::update_entry(pool, &info) {
+                    Ok(()) => {
+                        self.metrics.processed.fetch_add(1, Ordering::Relaxed);
+                        break;
+                    },
+                    Err(ref e) if e.is_transient() && attempts < self.max_attempts => {
+                        warn!(target: "analytics:warn", "Retrying exiting update for {} in {:?}: {}", info.visit, backoff, e);
+                        self.metrics.retries.fetch_add(1, Ordering::Relaxed);
+                        thread::sleep(backoff);
+                        if self.stopped() {
+                            return;
+                        }
+                        backoff = ::std::cmp::min(backoff * 2, self.max_backoff);
+                        attempts += 1;
+                    },
+                    Err(e) => {
+                        error!(target: "analytics:error", "Discarding exiting update for {} after {} attempts: {}", info.visit, attempts, e);
+                        self.metrics.failed.fetch_add(1, Ordering::Relaxed);
+                        break;
+                    },
+                }
+            }
+            self.finish(seq);
+        }
+    }
+
+    /// Wait for the next item, `None` once the queue is stopped
+    fn take(&self) -> Option<(u64, ExitingInfo)> {
+        let mut state = self.state.lock().expect("queue lock poisoned");
+        loop {
+            if state.stopped {
+                return None;
+            }
+            if let Some(item) = state.pending.pop_front() {
+                state.in_flight += 1;
+                return Some(item);
+            }
+            state = self.ready.wait(state).expect("queue lock poisoned");
+        }
+    }
+
+    fn finish(&self, seq: u64) {
+        let path = self.path(seq, "json");
+        if let Err(e) = fs::remove_file(&path) {
+            error!(target: "analytics:error", "Unable to remove {}: {}", path.display(), e);
+        }
+        self.state.lock().expect("queue lock poisoned").in_flight -= 1;
+    }
+
+    /// Write to a temporary file first so a crash never leaves a
+    /// partial item behind
+    fn write(&self, seq: u64, info: &ExitingInfo) -> Result<(), Error> {
+        let tmp = self.path(seq, "tmp");
+        let body = ::serde_json::to_vec(info).map_err(|e| Error::Other(format!("Unable to serialize queue item: {}", e)))?;
+        File::create(&tmp)
+            .and_then(|mut f| {
+                f.write_all(&body)?;
+                f.sync_all()
+            })
+            .map_err(|e| io_error("write", &tmp, e))?;
+        let path = self.path(seq, "json");
+        fs::rename(&tmp, &path).map_err(|e| io_error("write", &path, e))
+    }
+
+    fn path(&self, seq: u64, ext: &str) -> PathBuf {
+        self.dir.join(format!("{:020}.{}", seq, ext))
+    }
+
+    /// The Prometheus text format of the queue's metrics
+    pub fn render_metrics(&self) -> String {
+        let metrics = [
+            ("analytics_exiting_queue_depth", "gauge", "Exiting updates waiting to be applied", self.depth()),
+            ("analytics_exiting_processed_total", "counter", "Exiting updates applied", self.metrics.processed.load(Ordering::Relaxed)),
+            ("analytics_exiting_dropped_total", "counter", "Exiting updates rejected because the queue was full or unwritable", self.metrics.dropped.load(Ordering::Relaxed)),
+            ("analytics_exiting_failed_total", "counter", "Exiting updates discarded after a permanent error or too many attempts", self.metrics.failed.load(Ordering::Relaxed)),
+            ("analytics_exiting_retries_total", "counter", "Attempts to apply an exiting update that will be retried", self.metrics.retries.load(Ordering::Relaxed)),
+        ];
+        let mut ret = String::new();
+        for &(name, kind, help, value) in metrics.iter() {
+            ret.push_str(&format!("# HELP {} {}\n# TYPE {} {}\n{} {}\n", name, help, name, kind, name, value));
+        }
+        ret
+    }
+}
+
+fn sequence(path: &Path) -> Option<u64> {
+    if path.extension()? != "json" {
+        return None;
+    }
+    path.file_stem()?.to_str()?.parse().ok()
+}
+
+fn io_error(action: &str, path: &Path, e: ::std::io::Error) -> Error {
+    Error::Other(format!("Unable to {} {}: {}", action, path.display(), e))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use uuid::Uuid;
+
+    fn config(capacity: usize) -> QueueConfig {
+        QueueConfig {
+            path: ::std::env::temp_dir().join(format!("analytics-queue-{}", Uuid::new_v4().simple())),
+            capacity,
+            workers: 1,
+            max_backoff: 1,
+            max_attempts: 100,
+        }
+    }
+
+    fn exit(time: i64) -> ExitingInfo {
+        ExitingInfo {
+            visit: Uuid::new_v4(),
+            time,
+            link_clicked: None,
+        }
+    }
+
+    #[test]
+    fn recovers_and_drops() {
+        let config = config(2);
+        let queue = Queue::open(&config).unwrap();
+        assert!(queue.push(exit(1000)).unwrap());
+        assert!(queue.push(exit(2000)).unwrap());
+        assert!(!queue.push(exit(3000)).unwrap());
+        assert_eq!(queue.metrics.dropped.load(Ordering::Relaxed), 1);
+        File::create(config.path.join("00000000000000000009.tmp")).unwrap();
+        let reopened = Queue::open(&config).unwrap();
+        assert_eq!(reopened.depth(), 2);
+        let (first, info) = reopened.take().unwrap();
+        assert_eq!((first, info.time), (0, 1000));
+        assert!(!config.path.join("00000000000000000009.tmp").exists());
+        assert_eq!(reopened.state.lock().unwrap().next, 2);
+        fs::remove_dir_all(&config.path).unwrap();
+    }
+
+    #[test]
+    fn concurrent_pushes() {
+        let config = config(8);
+        let queue = Arc::new(Queue::open(&config).unwrap());
+        let pushes: Vec<_> = (0..16).map(|i| {
+            let queue = queue.clone();
+            thread::spawn(move || queue.push(exit(i)).unwrap())
+        }).collect();
+        let accepted = pushes.into_iter().map(|p| p.join().unwrap()).filter(|&ok| ok).count();
+        assert_eq!(accepted, 8);
+        assert_eq!(queue.depth(), 8);
+        assert_eq!(queue.metrics.dropped.load(Ordering::Relaxed), 8);
+        assert_eq!(fs::read_dir(&config.path).unwrap().count(), 8);
+        fs::remove_dir_all(&config.path).unwrap();
+    }
+
+    #[test]
+    fn retries_while_unavailable() {
+        let config = config(10);
+        let queue = Arc::new(Queue::open(&config).unwrap());
+        let db = ::config::DbConfig {
+            port: 1,
+            checkout_timeout: 1,
+            ..Default::default()
+        };
+        let pool = data::create_pool(&db).unwrap();
+        let workers = Queue::start(&queue, &pool, 1);
+        queue.push(exit(1000)).unwrap();
+        thread::sleep(Duration::from_secs(3));
+        assert!(queue.metrics.retries.load(Ordering::Relaxed) >= 1);
+        assert_eq!(queue.depth(), 1);
+        assert_eq!(fs::read_dir(&config.path).unwrap().count(), 1);
+        assert!(queue.render_metrics().contains("analytics_exiting_queue_depth 1\n"));
+        queue.stop();
+        for worker in workers {
+            worker.join().unwrap();
+        }
+        // still there for the next time the queue is opened
+        assert_eq!(Queue::open(&config).unwrap().depth(), 1);
+        fs::remove_dir_all(&config.path).unwrap();
+    }
+
+    #[test]
+    fn gives_up() {
+        let config = QueueConfig {
+            max_attempts: 2,
+            ..config(10)
+        };
+        let queue = Arc::new(Queue::open(&config).unwrap());
+        let db = ::config::DbConfig {
+            port: 1,
+            checkout_timeout: 1,
+            ..Default::default()
+        };
+        let pool = data::create_pool(&db).unwrap();
+        let workers = Queue::start(&queue, &pool, 1);
+        queue.push(exit(1000)).unwrap();
+        for _ in 0..200 {
+            if queue.metrics.failed.load(Ordering::Relaxed) == 1 {
+                break;
+            }
+            thread::sleep(Duration::from_millis(100));
+        }
+        // the worker finishes the item it gave up on before exiting
+        queue.stop();
+        for worker in workers {
+            worker.join().unwrap();
+        }
+        assert_eq!(queue.metrics.failed.load(Ordering::Relaxed), 1);
+        assert_eq!(queue.metrics.retries.load(Ordering::Relaxed), 1);
+        assert_eq!(queue.depth(), 0);
+        assert_eq!(fs::read_dir(&config.path).unwrap().count(), 0);
+        fs::remove_dir_all(&config.path).unwrap();
+    }
+
+    #[test]
+    fn applies_updates() {
+        let app = ::config::Config::load(None).expect("Unable to load config");
+        let pool = data::create_pool(&app.db).expect("Unable to create pool");
+        ::migrations::up(&pool.get().expect("Unable to connect")).expect("Unable to migrate");
+        let landing = ::test_support::landing("example.com", "https://example.com/queued");
+        let visit = data::add_entry(&pool, &landing, "8.8.8.8", "I'm a teapot").unwrap().visit;
+        let config = config(10);
+        let queue = Arc::new(Queue::open(&config).unwrap());
+        let workers = Queue::start(&queue, &pool, 2);
+        queue.push(ExitingInfo {
+            visit,
+            time: 4321,
+            link_clicked: None,
+        }).unwrap();
+        for _ in 0..50 {
+            if queue.metrics.processed.load(Ordering::Relaxed) == 1 && queue.depth() == 0 {
+                break;
+            }
+            thread::sleep(Duration::from_millis(100));
+        }
+        assert_eq!(queue.depth(), 0);
+        assert_eq!(fs::read_dir(&config.path).unwrap().count(), 0);
+        let conn = pool.get().unwrap();
+        let rows = conn.query("SELECT time_on_page FROM session WHERE visit_token = $1", &[&visit]).unwrap();
+        assert_eq!(rows.get(0).get::<_, Option<i64>>(0), Some(4321));
+        queue.stop();
+        for worker in workers {
+            worker.join().unwrap();
+        }
+        fs::remove_dir_all(&config.path).unwrap();
+    }
+}
@@ -0,0 +1,500 @@
+use std::{
+    collections::HashMap,
+    fs::read_to_string,
+    path::Path,
+    str::FromStr,
+};
+
+use postgres::Connection;
+use toml::from_str;
+use url::Url;
+
+use data::{add_previous, get_connection, Pool};
+use glob::matches;
+use reports::{Cell, Column, Kind, Table};
+use window::ReportWindow;
+use validation::ErrorBody;
+use super::Error;
+
+/// The rules used when none of the configured ones match
+static DEFAULT_RULES: &str = include_str!("referrers.toml");
+
+/// The kind of place a visitor came from
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum Channel {
+    Search,
+    Social,
+    Aggregator,
+    /// No referrer at all
+    Direct,
+    /// A referrer on the same site as the page
+    Internal,
+    Other,
+}
+
+impl FromStr for Channel {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, String> {
+        Ok(match s.to_lowercase().as_str() {
+            "search" => Channel::Search,
+            "social" => Channel::Social,
+            "aggregator" => Channel::Aggregator,
+            "direct" => Channel::Direct,
+            "internal" => Channel::Internal,
+            "other" => Channel::Other,
+            _ => return Err(format!("unknown channel {}", s)),
+        })
+    }
+}
+
+impl ::std::fmt::Display for Channel {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        match self {
+            Channel::Search => "search",
+            Channel::Social => "social",
+            Channel::Aggregator => "aggregator",
+            Channel::Direct => "direct",
+            Channel::Internal => "internal",
+            Channel::Other => "other",
+        }.fmt(f)
+    }
+}
+
+/// Referrers with a host matching one of `hosts` belong to
+/// `channel` and are reported as `source`
+#[derive(Deserialize, Debug, Clone)]
+pub(crate) struct Rule {
+    pub channel: Channel,
+    pub source: String,
+    pub hosts: Vec<String>,
+}
+
+#[derive(Deserialize, Debug, Clone, Default)]
+pub(crate) struct Rules {
+    #[serde(default, rename = "rule")]
+    rules: Vec<Rule>,
+}
+
+impl Rules {
+    /// The rules in `path` followed by the built in rules
+    pub fn load(path: Option<&Path>) -> Result<Self, Error> {
+        let mut ret = match path {
+            Some(path) => {
+                let s = read_to_string(path)
+                    .map_err(|e| Error::Other(format!("Unable to read referrer rules {}: {}", path.display(), e)))?;
+                Self::parse(&s)
+                    .map_err(|e| Error::Other(format!("Unable to parse referrer rules {}: {}", path.display(), e)))?
+            },
+            None => Self::default(),
+        };
+        let defaults = Self::parse(DEFAULT_RULES)
+            .map_err(|e| Error::Other(format!("Unable to parse the built in referrer rules: {}", e)))?;
+        ret.rules.extend(defaults.rules);
+        Ok(ret)
+    }
+
+    fn parse(s: &str) -> Result<Self, String> {
+        let ret: Self = from_str(s).map_err(|e| e.to_string())?;
+        for rule in &ret.rules {
+            if rule.source.is_empty() || rule.hosts.is_empty() {
+                return Err("every rule needs a source and at least one host".into());
+            }
+        }
+        Ok(ret)
+    }
+
+    /// The channel and source of a session that came from
+    /// `referrer` while landing on `site`
+    pub fn classify(&self, referrer: Option<&str>, site: Option<&str>) -> (Channel, String) {
+        let referrer = match referrer.map(str::trim) {
+            Some(referrer) if !referrer.is_empty() => referrer,
+            _ => return (Channel::Direct, "Direct".into()),
+        };
+        let host = match host(referrer) {
+            Some(host) => host,
+            None => return (Channel::Other, referrer.to_string()),
+        };
+        if site.and_then(self::host).map(|site| site == host).unwrap_or(false) {
+            return (Channel::Internal, host);
+        }
+        self.rules.iter()
+            .find(|rule| rule.hosts.iter().any(|pattern| matches(pattern, &host)))
+            .map(|rule| (rule.channel, rule.source.clone()))
+            .unwrap_or((Channel::Other, host))
+    }
+}
+
+/// A missing scheme is treated as `http`
+fn parse(url: &str) -> Option<Url> {
+    if url.contains("://") {
+        Url::parse(url).ok()
+    } else {
+        Url::parse(&format!("http://{}", url)).ok()
+    }
+}
+
+/// The lowercase host of a url without `www.`
+fn host(url: &str) -> Option<String> {
+    host_of(&parse(url)?)
+}
+
+fn host_of(url: &Url) -> Option<String> {
+    url.host_str()
+        .map(|host| host.to_lowercase().trim_start_matches("www.").to_string())
+}
+
+/// The Hacker News, Reddit or lobste.rs thread a referrer points
+/// at, or the subreddit or tag when it isn't a thread, along with
+/// the name of the site
+pub(crate) fn discussion(referrer: &str) -> Option<(&'static str, String)> {
+    let url = parse(referrer)?;
+    let host = host_of(&url)?;
+    let segments: Vec<&str> = url.path_segments()
+        .map(|s| s.filter(|s| !s.is_empty()).collect())
+        .unwrap_or_default();
+    if host == "news.ycombinator.com" {
+        return url.query_pairs()
+            .find(|(name, _)| name == "id")
+            .map(|(_, id)| ("Hacker News", format!("/item?id={}", id)));
+    }
+    if host == "lobste.rs" {
+        return match segments.as_slice() {
+            ["s", id, ..] => Some(("Lobsters", format!("/s/{}", id))),
+            ["t", tag, ..] => Some(("Lobsters", format!("/t/{}", tag))),
+            _ => None,
+        };
+    }
+    if host == "reddit.com" || host.ends_with(".reddit.com") {
+        return match segments.as_slice() {
+            ["r", sub, "comments", id, ..] => Some(("Reddit", format!("/r/{}/comments/{}", sub.to_lowercase(), id))),
+            ["r", sub, ..] => Some(("Reddit", format!("/r/{}", sub.to_lowercase()))),
+            _ => None,
+        };
+    }
+    None
+}
+
+/// The query parameters search engines put the search terms in
+const SEARCH_PARAMS: &[&str] = &["q", "query", "p", "text", "wd", "keyword"];
+
+/// The search terms a referrer still carries, most engines no
+/// longer send them. Taken before the referrer is normalized
+/// since that drops them unless they are in `urls.allow_params`
+pub(crate) fn search_terms(referrer: &str) -> Option<String> {
+    parse(referrer)?
+        .query_pairs()
+        .find(|(name, value)| SEARCH_PARAMS.contains(&name.as_ref()) && !value.trim().is_empty())
+        .map(|(_, value)| value.trim().to_lowercase())
+}
+
+/// A classified referrer and how many sessions it sent
+struct Referral {
+    referrer: Option<String>,
+    channel: Channel,
+    source: String,
+    /// The site and thread, taken from the referrer for sessions
+    /// recorded before they were stored
+    discussion: Option<(String, String)>,
+    search_terms: Option<String>,
+    sessions: i64,
+}
+
+fn referrals(conn: &Connection, rules: &Rules, window: &ReportWindow, site: Option<&str>) -> Result<Vec<Referral>, Error> {
+    Ok(conn.query("SELECT *
+                FROM referrer_sessions($1, $2, $3)",
+                &[&window.from, &window.to, &site])?
+        .iter()
+        .map(|r| {
+            let referrer: Option<String> = r.get(0);
+            let site: Option<String> = r.get(1);
+            let (channel, source) = rules.classify(referrer.as_deref(), site.as_deref());
+            let stored: (Option<String>, Option<String>) = (r.get(3), r.get(4));
+            let discussion = match stored {
+                (Some(site), Some(thread)) => Some((site, thread)),
+                _ => referrer.as_ref()
+                    .and_then(|r| discussion(r))
+                    .map(|(site, thread)| (site.to_string(), thread)),
+            };
+            let search_terms = r.get::<_, Option<String>>(5)
+                .or_else(|| referrer.as_ref().and_then(|r| search_terms(r)));
+            Referral {
+                referrer,
+                channel,
+                source,
+                discussion,
+                search_terms,
+                sessions: r.get(2),
+            }
+        })
+        .collect())
+}
+
+/// Sessions per channel, source, discussion and search terms,
+/// these are part of the main report. With `previous` the sessions
+/// are paired with that period's.
+pub(crate) fn tables(conn: &Connection, rules: &Rules, window: &ReportWindow, previous: Option<&ReportWindow>, site: Option<&str>, scope: &str) -> Result<Vec<Table>, Error> {
+    let build = |window: &ReportWindow| -> Result<Vec<Table>, Error> {
+        let referrals = referrals(conn, rules, window, site)?;
+        Ok(vec![
+            channels(&referrals, scope),
+            sources(&referrals, scope, None),
+            discussions(&referrals, scope),
+            searches(&referrals, scope),
+        ])
+    };
+    compared(build(window)?, previous.map(build).transpose()?, &[1, 2, 2, 2])
+}
+
+/// The channel and source totals along with every referrer that
+/// was classified as `channel` and `source`, when provided the
+/// sources are limited to `channel` as well. With `compare` the
+/// sessions in each table are paired with the previous period's.
+pub(crate) fn report(pool: &Pool, rules: &Rules, window: &ReportWindow, site: Option<&str>, channel: Option<&str>, source: Option<&str>, compare: bool) -> Result<Vec<Table>, Error> {
+    let channel = match channel.filter(|c| !c.is_empty()) {
+        Some(channel) => Some(channel.parse::<Channel>()
+            .map_err(|msg| Error::Invalid(400, ErrorBody::new(msg)))?),
+        None => None,
+    };
+    let source = source.filter(|s| !s.is_empty());
+    let conn = get_connection(pool)?;
+    let scope = window.scope(site);
+    let build = |window: &ReportWindow| -> Result<Vec<Table>, Error> {
+        let referrals = referrals(&conn, rules, window, site)?;
+        Ok(vec![
+            channels(&referrals, &scope),
+            sources(&referrals, &scope, channel),
+            referrers(&referrals, &scope, channel, source),
+        ])
+    };
+    let previous = if compare {
+        Some(build(&window.previous().map_err(Error::Other)?)?)
+    } else {
+        None
+    };
+    compared(build(window)?, previous, &[1, 2, 3])
+}
+
+/// Pair each of `current` with the same table from `previous`,
+/// `counts` is the index of the sessions column in each
+pub(crate) fn compared(current: Vec<Table>, previous: Option<Vec<Table>>, counts: &[usize]) -> Result<Vec<Table>, Error> {
+    Ok(match previous {
+        Some(previous) => current.into_iter()
+            .zip(previous)
+            .zip(counts)
+            .map(|((current, previous), &count)| add_previous(current, previous, count))
+            .collect(),
+        None => current,
+    })
+}
+
+/// Every referrer that was classified as `channel` and `source`
+fn referrers(referrals: &[Referral], scope: &str, channel: Option<Channel>, source: Option<&str>) -> Table {
+    let mut table = Table::new(format!("{} Referrers", scope), vec![
+        Column::new("Referer", Kind::Url),
+        Column::new("Channel", Kind::Text),
+        Column::new("Source", Kind::Text),
+        Column::new("Sessions", Kind::Int),
+    ]);
+    // the same referrer is listed once per site
+    let mut counts = HashMap::new();
+    for r in referrals.iter()
+        .filter(|r| channel.map(|c| c == r.channel).unwrap_or(true))
+        .filter(|r| source.map(|s| s.eq_ignore_ascii_case(&r.source)).unwrap_or(true)) {
+        *counts.entry((r.referrer.clone(), r.channel, r.source.clone())).or_insert(0) += r.sessions;
+    }
+    table.rows = ranked(counts).into_iter()
+        .map(|((referrer, channel, source), ct)| vec![
+            referrer.map(Cell::Url).unwrap_or(Cell::Null),
+            Cell::Text(channel.to_string()),
+            Cell::Text(source),
+            Cell::Int(ct),
+        ])
+        .collect();
+    table
+}
+
+fn channels(referrals: &[Referral], scope: &str) -> Table {
+    let mut counts = HashMap::new();
+    for r in referrals {
+        *counts.entry(r.channel).or_insert(0) += r.sessions;
+    }
+    let total: i64 = counts.values().sum();
+    let mut table = Table::new(format!("{} Channels", scope), vec![
+        Column::new("Channel", Kind::Text),
+        Column::new("Sessions", Kind::Int),
+        Column::new("Share", Kind::Percent),
+    ]);
+    table.rows = ranked(counts).into_iter()
+        .map(|(channel, ct)| vec![
+            Cell::Text(channel.to_string()),
+            Cell::Int(ct),
+            Cell::Percent(ct as f64 / total as f64),
+        ])
+        .collect();
+    table
+}
+
+fn sources(referrals: &[Referral], scope: &str, channel: Option<Channel>) -> Table {
+    let mut counts = HashMap::new();
+    for r in referrals.iter().filter(|r| channel.map(|c| c == r.channel).unwrap_or(true)) {
+        *counts.entry((r.channel, r.source.clone())).or_insert(0) += r.sessions;
+    }
+    let mut table = Table::new(format!("{} Sources", scope), vec![
+        Column::new("Channel", Kind::Text),
+        Column::new("Source", Kind::Text),
+        Column::new("Sessions", Kind::Int),
+    ]);
+    table.rows = ranked(counts).into_iter()
+        .map(|((channel, source), ct)| vec![
+            Cell::Text(channel.to_string()),
+            Cell::Text(source),
+            Cell::Int(ct),
+        ])
+        .collect();
+    table
+}
+
+fn discussions(referrals: &[Referral], scope: &str) -> Table {
+    let mut counts = HashMap::new();
+    for r in referrals {
+        if let Some(ref discussion) = r.discussion {
+            *counts.entry(discussion.clone()).or_insert(0) += r.sessions;
+        }
+    }
+    let mut table = Table::new(format!("{} Discussions", scope), vec![
+        Column::new("Site", Kind::Text),
+        Column::new("Discussion", Kind::Text),
+        Column::new("Sessions", Kind::Int),
+    ]);
+    table.rows = ranked(counts).into_iter()
+        .map(|((site, thread), ct)| vec![Cell::Text(site), Cell::Text(thread), Cell::Int(ct)])
+        .collect();
+    table
+}
+
+fn searches(referrals: &[Referral], scope: &str) -> Table {
+    let mut counts = HashMap::new();
+    for r in referrals.iter().filter(|r| r.channel == Channel::Search) {
+        if let Some(ref terms) = r.search_terms {
+            *counts.entry((r.source.clone(), terms.clone())).or_insert(0) += r.sessions;
+        }
+    }
+    let mut table = Table::new(format!("{} Search Terms", scope), vec![
+        Column::new("Source", Kind::Text),
+        Column::new("Terms", Kind::Text),
+        Column::new("Sessions", Kind::Int),
+    ]);
+    table.rows = ranked(counts).into_iter()
+        .map(|((source, terms), ct)| vec![Cell::Text(source), Cell::Text(terms), Cell::Int(ct)])
+        .collect();
+    table
+}
+
+/// Most sessions first, ties are broken by key
+pub(crate) fn ranked<K: Ord>(counts: HashMap<K, i64>) -> Vec<(K, i64)> {
+    let mut ret: Vec<(K, i64)> = counts.into_iter().collect();
+    ret.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+    ret
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn classify() {
+        let rules = Rules::load(None).unwrap();
+        let site = Some("https://wiredforge.com");
+        let check = |referrer: Option<&str>, channel: Channel, source: &str| {
+            assert_eq!(rules.classify(referrer, site), (channel, source.to_string()), "{:?}", referrer);
+        };
+        check(None, Channel::Direct, "Direct");
+        check(Some(""), Channel::Direct, "Direct");
+        check(Some("https://www.google.com/"), Channel::Search, "Google");
+        check(Some("https://google.co.uk/search?q=rust"), Channel::Search, "Google");
+        check(Some("android-app://com.google.android.googlequicksearchbox"), Channel::Search, "Google");
+        check(Some("https://DuckDuckGo.com"), Channel::Search, "DuckDuckGo");
+        check(Some("https://old.reddit.com/r/rust/comments/abc/title/def"), Channel::Aggregator, "Reddit");
+        check(Some("https://news.ycombinator.com/item?id=1"), Channel::Aggregator, "Hacker News");
+        check(Some("https://t.co/xyz"), Channel::Social, "Twitter");
+        check(Some("https://www.wiredforge.com/blog"), Channel::Internal, "wiredforge.com");
+        check(Some("https://example.com/links"), Channel::Other, "example.com");
+        check(Some("not a url at all"), Channel::Other, "not a url at all");
+    }
+
+    #[test]
+    fn custom_rules() {
+        let mut rules = Rules::parse(r#"
+            [[rule]]
+            channel = "social"
+            source = "Rust Users Forum"
+            hosts = ["users.rust-lang.org"]
+
+            [[rule]]
+            channel = "search"
+            source = "Internal Search"
+            hosts = ["google.com"]
+        "#).unwrap();
+        rules.rules.extend(Rules::load(None).unwrap().rules);
+        assert_eq!(rules.classify(Some("https://users.rust-lang.org/t/1"), None), (Channel::Social, "Rust Users Forum".into()));
+        assert_eq!(rules.classify(Some("https://google.com"), None), (Channel::Search, "Internal Search".into()));
+        assert!(Rules::parse("[[rule]]\nchannel = \"search\"\nsource = \"x\"\nhosts = []").is_err());
+        assert!(Rules::parse("[[rule]]\nchannel = \"email\"\nsource = \"x\"\nhosts = [\"x\"]").is_err());
+    }
+
+    #[test]
+    fn threads() {
+        let check = |referrer: &str, expected: Option<(&'static str, &str)>| {
+            assert_eq!(discussion(referrer), expected.map(|(site, thread)| (site, thread.to_string())), "{}", referrer);
+        };
+        check("http://reddit.com/r/rust", Some(("Reddit", "/r/rust")));
+        check("https://old.reddit.com/r/Rust/comments/abc123/some_title/def456/", Some(("Reddit", "/r/rust/comments/abc123")));
+        check("https://reddit.com/", None);
+        check("https://news.ycombinator.com/item?id=123", Some(("Hacker News", "/item?id=123")));
+        check("https://news.ycombinator.com/", None);
+        check("https://lobste.rs/s/abc123/a_title", Some(("Lobsters", "/s/abc123")));
+        check("https://lobste.rs/t/rust", Some(("Lobsters", "/t/rust")));
+        check("https://lobste.rs/", None);
+        check("https://example.com/r/rust", None);
+    }
+
+    #[test]
+    fn search_queries() {
+        assert_eq!(search_terms("https://www.google.com/search?q=Rust+Warp+ "), Some("rust warp".into()));
+        assert_eq!(search_terms("https://search.yahoo.com/search?p=getpid"), Some("getpid".into()));
+        assert_eq!(search_terms("https://duckduckgo.com/?q="), None);
+        assert_eq!(search_terms("https://www.google.com/"), None);
+    }
+
+    #[test]
+    fn totals() {
+        let rules = Rules::load(None).unwrap();
+        let referrals: Vec<Referral> = [
+            (Some("https://www.google.com/"), 3),
+            (Some("https://google.com/search?q=rust"), 2),
+            (Some("https://reddit.com/r/rust"), 4),
+            (None, 1),
+        ].iter().map(|&(referrer, sessions)| {
+            let (channel, source) = rules.classify(referrer, None);
+            Referral {
+                referrer: referrer.map(String::from),
+                channel,
+                source,
+                discussion: referrer.and_then(discussion).map(|(site, thread)| (site.to_string(), thread)),
+                search_terms: referrer.and_then(search_terms),
+                sessions,
+            }
+        }).collect();
+        let channels = channels(&referrals, "test");
+        assert_eq!(channels.rows, vec![
+            vec![Cell::Text("search".into()), Cell::Int(5), Cell::Percent(0.5)],
+            vec![Cell::Text("aggregator".into()), Cell::Int(4), Cell::Percent(0.4)],
+            vec![Cell::Text("direct".into()), Cell::Int(1), Cell::Percent(0.1)],
+        ]);
+        let sources = sources(&referrals, "test", Some(Channel::Search));
+        assert_eq!(sources.rows, vec![vec![Cell::Text("search".into()), Cell::Text("Google".into()), Cell::Int(5)]]);
+        assert_eq!(discussions(&referrals, "test").rows, vec![vec![Cell::Text("Reddit".into()), Cell::Text("/r/rust".into()), Cell::Int(4)]]);
+        assert_eq!(searches(&referrals, "test").rows, vec![vec![Cell::Text("Google".into()), Cell::Text("rust".into()), Cell::Int(2)]]);
+    }
+}
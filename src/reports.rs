@@ -1,29 +1,216 @@
 use term_table::{Table as TTable, TableStyle, table_cell::{TableCell, Alignment}, row::Row};
 use colored::*;
+
+use time_parsing::serialize_duration;
+
 static TEMPLATE: &str = include_str!("report.html");
 static TABLE_STYLE: &str = r#"border:1px solid black;border-collapse: collapse;margin-bottom: 10px;"#;
 static HEADER_STYLE: &str = r#"border:1px solid black;font-weight:bold;"#;
 static CELL_STYLE: &str = r#"border:1px solid black;"#;
+/// URLs longer than this are cut short in the human readable reports
+const MAX_URL_LEN: usize = 100;
 
-#[derive(Serialize)]
+/// How the values in a column are stored, which decides how
+/// they are displayed and how they are aligned by default
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Kind {
+    Int,
+    /// A fractional number, displayed with two decimal places
+    Decimal,
+    /// Milliseconds, displayed as an ISO 8601 duration
+    Duration,
+    /// A ratio, `0.5` is displayed as `50.0%`
+    Percent,
+    Url,
+    Text,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Align {
+    Left,
+    Center,
+    Right,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Column {
+    pub name: String,
+    pub kind: Kind,
+    pub align: Align,
+    pub unit: Option<String>,
+    /// Positive values are displayed with a leading `+`
+    pub signed: bool,
+}
+
+impl Column {
+    /// Numbers are right aligned and everything else is left aligned
+    pub fn new(name: &str, kind: Kind) -> Self {
+        let align = match kind {
+            Kind::Int | Kind::Decimal | Kind::Duration | Kind::Percent => Align::Right,
+            Kind::Url | Kind::Text => Align::Left,
+        };
+        Self {
+            name: name.to_string(),
+            kind,
+            align,
+            unit: None,
+            signed: false,
+        }
+    }
+
+    pub fn unit(mut self, unit: &str) -> Self {
+        self.unit = Some(unit.to_string());
+        self
+    }
+
+    pub fn signed(mut self) -> Self {
+        self.signed = true;
+        self
+    }
+
+    /// The column's name followed by its unit, if it has one
+    pub fn header(&self) -> String {
+        match self.unit {
+            Some(ref unit) => format!("{} ({})", self.name, unit),
+            None => self.name.clone(),
+        }
+    }
+}
+
+/// A single value in a report, machine readable formats get the
+/// raw value while the human readable ones use `Cell::display`
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(untagged)]
+pub enum Cell {
+    Int(i64),
+    Decimal(f64),
+    /// Milliseconds
+    Duration(i64),
+    /// A ratio, not multiplied by 100
+    Percent(f64),
+    Url(String),
+    Text(String),
+    /// A value that can't be computed, like a change from 0
+    Null,
+}
+
+impl Cell {
+    /// Format this value for people, URLs are truncated so one
+    /// long referrer doesn't blow out the width of a table
+    pub fn display(&self, column: &Column) -> String {
+        match *self {
+            Cell::Int(i) if column.signed => format!("{:+}", i),
+            Cell::Int(i) => i.to_string(),
+            Cell::Decimal(d) => format!("{:.2}", d),
+            Cell::Duration(ms) => serialize_duration(ms),
+            Cell::Percent(p) if column.signed => format!("{:+.1}%", p * 100.0),
+            Cell::Percent(p) => format!("{:.1}%", p * 100.0),
+            Cell::Url(ref url) => truncate(url, MAX_URL_LEN),
+            Cell::Text(ref s) => s.clone(),
+            Cell::Null => "n/a".to_string(),
+        }
+    }
+
+    /// The untruncated value with durations in milliseconds, for CSV
+    pub fn raw(&self) -> String {
+        match *self {
+            Cell::Int(i) |
+            Cell::Duration(i) => i.to_string(),
+            Cell::Decimal(f) |
+            Cell::Percent(f) => f.to_string(),
+            Cell::Url(ref s) |
+            Cell::Text(ref s) => s.clone(),
+            Cell::Null => String::new(),
+        }
+    }
+}
+
+fn truncate(s: &str, max: usize) -> String {
+    if s.chars().count() <= max {
+        return s.to_string();
+    }
+    let mut ret: String = s.chars().take(max.saturating_sub(3)).collect();
+    ret.push_str("...");
+    ret
+}
+
+#[derive(Debug, Serialize)]
 pub struct Table {
-    pub headers: Vec<String>,
-    pub rows: Vec<Vec<String>>,
     pub name: String,
+    /// Shown under the name, like when a table isn't compared
+    /// with the previous period
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub note: Option<String>,
+    pub columns: Vec<Column>,
+    pub rows: Vec<Vec<Cell>>,
 }
 
 impl Table {
-    pub fn new(name: String, headers: Vec<String>) -> Self {
+    pub fn new(name: String, columns: Vec<Column>) -> Self {
         Self {
             name,
-            headers,
+            note: None,
+            columns,
             rows: Vec::new(),
         }
     }
+
+    fn headers(&self) -> Vec<String> {
+        self.columns.iter().map(Column::header).collect()
+    }
+
+    /// Each row paired with the column it belongs to, rows shorter
+    /// than the header are left short
+    fn cells<'a>(&'a self, row: &'a [Cell]) -> impl Iterator<Item = (&'a Column, &'a Cell)> {
+        self.columns.iter().zip(row.iter())
+    }
+}
+
+/// The table in `tables` called `name` once the site and window
+/// every name starts with are left off
+#[cfg(test)]
+pub(crate) fn table<'a>(tables: &'a [Table], name: &str) -> &'a Table {
+    tables.iter()
+        .find(|t| t.name.ends_with(&format!(" {}", name)))
+        .unwrap_or_else(|| panic!("no {} table in {:?}", name, tables.iter().map(|t| &t.name).collect::<Vec<_>>()))
+}
+
+/// The already formatted values the HTML template renders
+#[derive(Serialize)]
+struct HtmlTable {
+    name: String,
+    note: Option<String>,
+    headers: Vec<String>,
+    rows: Vec<Vec<HtmlCell>>,
+}
+
+#[derive(Serialize)]
+struct HtmlCell {
+    value: String,
+    align: Align,
+}
+
+impl<'a> From<&'a Table> for HtmlTable {
+    fn from(table: &'a Table) -> Self {
+        Self {
+            name: table.name.clone(),
+            note: table.note.clone(),
+            headers: table.headers(),
+            rows: table.rows.iter().map(|row| {
+                table.cells(row).map(|(column, cell)| HtmlCell {
+                    value: cell.display(column),
+                    align: column.align,
+                }).collect()
+            }).collect(),
+        }
+    }
 }
 
 pub fn generate_report(tables: Vec<Table>) -> Result<String, String> {
     use tera::{Tera, Context};
+    let tables: Vec<HtmlTable> = tables.iter().map(HtmlTable::from).collect();
     let mut ctx = Context::new();
     ctx.insert("tables", &tables);
     ctx.insert("table_style", TABLE_STYLE);
@@ -32,23 +219,163 @@ pub fn generate_report(tables: Vec<Table>) -> Result<String, String> {
     Tera::one_off(TEMPLATE, &ctx, true).map_err(|e| format!("{:?}", e))
 }
 
-pub fn generate_ascii_report(tables: &Vec<Table>) -> String{
+/// The machine and human readable representations the report
+/// endpoints can respond with
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+    /// Box drawn tables with ANSI colors, for terminals
+    Ascii,
+    /// Box drawn tables without any escape codes
+    Text,
+    Json,
+    Csv,
+}
+
+impl Format {
+    /// Pick a format from the `?format=` parameter, falling back to
+    /// the `Accept` header and finally colored ASCII. An unknown
+    /// `format` value is an error while an `Accept` header we don't
+    /// understand just gets the default.
+    pub fn negotiate(format: Option<&str>, accept: Option<&str>) -> Result<Self, String> {
+        if let Some(format) = format {
+            return match format {
+                "ascii" => Ok(Format::Ascii),
+                "text" | "plain" => Ok(Format::Text),
+                "json" => Ok(Format::Json),
+                "csv" => Ok(Format::Csv),
+                _ => Err(format!("unknown format {}, expected ascii, text, json or csv", format)),
+            };
+        }
+        let accept = match accept {
+            Some(accept) => accept,
+            None => return Ok(Format::Ascii),
+        };
+        let mut ranges: Vec<(&str, f32)> = accept.split(',')
+            .map(|range| {
+                let mut parts = range.split(';').map(str::trim);
+                let media = parts.next().unwrap_or("");
+                let q = parts
+                    .filter_map(|p| p.strip_prefix("q="))
+                    .filter_map(|q| q.parse().ok())
+                    .next()
+                    .unwrap_or(1.0);
+                (media, q)
+            })
+            .filter(|&(_, q)| q > 0.0)
+            .collect();
+        ranges.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap_or(::std::cmp::Ordering::Equal));
+        for (media, _) in ranges {
+            match media {
+                "application/json" => return Ok(Format::Json),
+                "text/csv" => return Ok(Format::Csv),
+                "text/plain" => return Ok(Format::Text),
+                "*/*" | "text/*" => return Ok(Format::Ascii),
+                _ => (),
+            }
+        }
+        Ok(Format::Ascii)
+    }
+
+    pub fn content_type(self) -> &'static str {
+        match self {
+            Format::Ascii |
+            Format::Text => "text/plain; charset=utf-8",
+            Format::Json => "application/json",
+            Format::Csv => "text/csv; charset=utf-8",
+        }
+    }
+
+    pub fn render(self, tables: &[Table]) -> Result<String, String> {
+        Ok(match self {
+            Format::Ascii => generate_ascii_report(tables),
+            Format::Text => generate_text_report(tables),
+            Format::Json => generate_json_report(tables)?,
+            Format::Csv => generate_csv_report(tables),
+        })
+    }
+}
+
+pub fn generate_ascii_report(tables: &[Table]) -> String {
+    render_tables(tables, true)
+}
+
+/// The same box drawn tables as `generate_ascii_report` without
+/// any ANSI color codes
+pub fn generate_text_report(tables: &[Table]) -> String {
+    render_tables(tables, false)
+}
+
+pub fn generate_json_report(tables: &[Table]) -> Result<String, String> {
+    ::serde_json::to_string(tables).map_err(|e| format!("{}", e))
+}
+
+/// Each table is written as a section, a row with the table's name
+/// and its note, if it has one, followed by the header row and the
+/// data rows. Sections are separated by an empty line.
+pub fn generate_csv_report(tables: &[Table]) -> String {
+    let mut ret = String::new();
+    for (i, table) in tables.iter().enumerate() {
+        if i > 0 {
+            ret.push_str("\r\n");
+        }
+        push_csv_row(&mut ret, ::std::iter::once(table.name.clone()).chain(table.note.clone()));
+        push_csv_row(&mut ret, table.headers().into_iter());
+        for row in table.rows.iter() {
+            push_csv_row(&mut ret, row.iter().map(Cell::raw));
+        }
+    }
+    ret
+}
+
+fn push_csv_row(out: &mut String, cells: impl Iterator<Item = String>) {
+    for (i, cell) in cells.enumerate() {
+        if i > 0 {
+            out.push(',');
+        }
+        if cell.contains(&[',', '"', '\r', '\n'][..]) {
+            out.push('"');
+            out.push_str(&cell.replace('"', "\"\""));
+            out.push('"');
+        } else {
+            out.push_str(&cell);
+        }
+    }
+    out.push_str("\r\n");
+}
+
+fn render_tables(tables: &[Table], color: bool) -> String {
     let mut ret = String::new();
     for table in tables {
-        let columns = table.headers.len();
+        let columns = table.columns.len();
         let mut t = TTable::new();
         debug!("generating {} with width {}", table.name, columns);
         t.style = TableStyle::extended();
+        let name = if color {
+            table.name.bold().blue().to_string()
+        } else {
+            table.name.clone()
+        };
         t.add_row(Row::new(vec![
-            TableCell::new_with_alignment(&table.name.bold().blue(), columns, Alignment::Center),
+            TableCell::new_with_alignment(name, columns, Alignment::Center),
         ]));
+        if let Some(ref note) = table.note {
+            t.add_row(Row::new(vec![
+                TableCell::new_with_alignment(note, columns, Alignment::Center),
+            ]));
+        }
         t.add_row(Row::new(
-            table.headers.iter().map(|h| TableCell::new_with_alignment(&h.bold(), 1, Alignment::Center))
+            table.headers().into_iter().map(|h| if color {
+                TableCell::new_with_alignment(h.bold(), 1, Alignment::Center)
+            } else {
+                TableCell::new_with_alignment(h, 1, Alignment::Center)
+            })
         ));
 
         for row in table.rows.iter() {
             t.add_row(Row::new(
-                row.iter().map(|r| TableCell::new(&r))
+                table.cells(row).map(|(column, cell)| {
+                    TableCell::new_with_alignment(cell.display(column), 1, column.align.into())
+                })
             ));
         }
         ret.push_str(&t.render());
@@ -57,36 +384,152 @@ pub fn generate_ascii_report(tables: &Vec<Table>) -> String{
     ret
 }
 
+impl From<Align> for Alignment {
+    fn from(align: Align) -> Self {
+        match align {
+            Align::Left => Alignment::Left,
+            Align::Center => Alignment::Center,
+            Align::Right => Alignment::Right,
+        }
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
+
+    fn text_table(name: &str, headers: &[&str], row: &[&str]) -> Table {
+        let mut table = Table::new(name.to_string(), headers.iter().map(|h| Column::new(h, Kind::Text)).collect());
+        table.rows.push(row.iter().map(|c| Cell::Text(c.to_string())).collect());
+        table
+    }
+
+    fn typed_table() -> Table {
+        let mut table = Table::new("TYPED".to_string(), vec![
+            Column::new("Page", Kind::Url),
+            Column::new("Views", Kind::Int).unit("views"),
+            Column::new("Change", Kind::Int).signed(),
+            Column::new("% Change", Kind::Percent).signed(),
+            Column::new("Median Time", Kind::Duration),
+        ]);
+        table.rows.push(vec![
+            Cell::Url(format!("http://example.com/{}", "a".repeat(200))),
+            Cell::Int(15),
+            Cell::Int(5),
+            Cell::Percent(0.5),
+            Cell::Duration(61_500),
+        ]);
+        table.rows.push(vec![
+            Cell::Url("http://example.com/b".to_string()),
+            Cell::Int(0),
+            Cell::Int(-2),
+            Cell::Null,
+            Cell::Duration(0),
+        ]);
+        table
+    }
+
     #[test]
     fn tables() {
-        let mut table = Table::new("TEST ONE".to_string(), vec!["one".to_string(), "two".to_string()]);
-        table.rows.push(vec!["one".to_string(), "two".to_string()]);
         let tables = vec![
-            table
+            text_table("TEST ONE", &["one", "two"], &["one", "two"]),
+            typed_table(),
         ];
-        println!("{}", generate_report(tables).unwrap());
+        let html = generate_report(tables).unwrap();
+        println!("{}", html);
+        assert!(html.contains("text-align:right;\">+50.0%</td>"));
+        assert!(html.contains("Views (views)"));
+    }
+
+    #[test]
+    fn display() {
+        let table = typed_table();
+        let rendered: Vec<Vec<String>> = table.rows.iter()
+            .map(|row| table.cells(row).map(|(col, cell)| cell.display(col)).collect())
+            .collect();
+        assert_eq!(rendered[0][0].chars().count(), MAX_URL_LEN);
+        assert!(rendered[0][0].ends_with("..."));
+        assert_eq!(&rendered[0][1..], &["15", "+5", "+50.0%", "PT0H1M1.500S"]);
+        assert_eq!(&rendered[1][1..], &["0", "-2", "n/a", "P0D"]);
+        assert_eq!(truncate("ééééé", 4), "é...");
     }
 
     #[test]
     fn ascii() {
-        let mut table = Table::new("ASCII TEST".to_string(), vec!["one hundred".to_string(), "two".to_string()]);
-        table.rows.push(vec!["one".to_string(), "two thousand".to_string()]);
         let tables = vec![
-            table
+            text_table("ASCII TEST", &["one hundred", "two"], &["one", "two thousand"]),
+            typed_table(),
         ];
         println!("{}", generate_ascii_report(&tables));
     }
 
+    #[test]
+    fn text_has_no_color() {
+        let text = generate_text_report(&[text_table("PLAIN", &["one"], &["two"])]);
+        assert!(!text.contains('\u{1b}'));
+        assert!(text.contains("PLAIN"));
+    }
+
+    #[test]
+    fn notes() {
+        let noted = || {
+            let mut table = text_table("NOTED", &["one"], &["two"]);
+            table.note = Some("not compared".to_string());
+            table
+        };
+        assert!(generate_text_report(&[noted()]).contains("not compared"));
+        assert!(generate_report(vec![noted()]).unwrap().contains("<p>not compared</p>"));
+        assert!(generate_csv_report(&[noted()]).starts_with("NOTED,not compared\r\n"));
+        let json: ::serde_json::Value = ::serde_json::from_str(&generate_json_report(&[noted()]).unwrap()).unwrap();
+        assert_eq!(json[0]["note"], "not compared");
+        let json: ::serde_json::Value = ::serde_json::from_str(&generate_json_report(&[typed_table()]).unwrap()).unwrap();
+        assert!(json[0].get("note").is_none());
+    }
+
+    #[test]
+    fn csv() {
+        let table = text_table("CSV", &["page", "count"], &["/a,b", "1"]);
+        let mut quoted = text_table("QUOTED", &["page"], &["say \"hi\""]);
+        quoted.columns[0].unit = Some("text".to_string());
+        let other = Table::new("EMPTY".to_string(), vec![Column::new("x", Kind::Int)]);
+        assert_eq!(
+            generate_csv_report(&[table, quoted, other]),
+            "CSV\r\npage,count\r\n\"/a,b\",1\r\n\r\nQUOTED\r\npage (text)\r\n\"say \"\"hi\"\"\"\r\n\r\nEMPTY\r\nx\r\n"
+        );
+        let typed = generate_csv_report(&[typed_table()]);
+        let rows: Vec<&str> = typed.split("\r\n").collect();
+        assert!(rows[2].starts_with(&format!("http://example.com/{},15,5,0.5,61500", "a".repeat(200))));
+        assert_eq!(rows[3], "http://example.com/b,0,-2,,0");
+    }
+
+    #[test]
+    fn json() {
+        let json: ::serde_json::Value = ::serde_json::from_str(&generate_json_report(&[typed_table()]).unwrap()).unwrap();
+        assert_eq!(json[0]["name"], "TYPED");
+        assert_eq!(json[0]["columns"][1]["kind"], "int");
+        assert_eq!(json[0]["columns"][1]["unit"], "views");
+        assert_eq!(json[0]["columns"][1]["align"], "right");
+        assert_eq!(json[0]["rows"][0][3], 0.5);
+        assert_eq!(json[0]["rows"][0][4], 61_500);
+        assert_eq!(json[0]["rows"][1][3], ::serde_json::Value::Null);
+    }
+
+    #[test]
+    fn negotiate() {
+        assert_eq!(Format::negotiate(None, None), Ok(Format::Ascii));
+        assert_eq!(Format::negotiate(None, Some("*/*")), Ok(Format::Ascii));
+        assert_eq!(Format::negotiate(None, Some("application/json")), Ok(Format::Json));
+        assert_eq!(Format::negotiate(None, Some("text/html, text/csv;q=0.9, */*;q=0.1")), Ok(Format::Csv));
+        assert_eq!(Format::negotiate(None, Some("text/plain;q=0.5, application/json")), Ok(Format::Json));
+        assert_eq!(Format::negotiate(Some("text"), Some("application/json")), Ok(Format::Text));
+        assert!(Format::negotiate(Some("xml"), None).is_err());
+    }
+
     #[test]
     fn ascii_uneven() {
-        let mut table = Table::new("UNEVEN".to_string(), vec!["one hundred two".to_string(), "two ad".to_string()]);
-        table.rows.push(vec!["one".to_string(), "two".to_string()]);
         let tables = vec![
-            table
+            text_table("UNEVEN", &["one hundred two", "two ad"], &["one", "two"]),
         ];
         println!("{}", generate_ascii_report(&tables));
     }
-}
\ No newline at end of file
+}
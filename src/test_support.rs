@@ -0,0 +1,19 @@
+use chrono::Utc;
+
+use super::LandingInfo;
+
+/// A landing on `page` now, without a referrer, cookie, previous
+/// visit, campaign or referrer details, for tests to fill in what they need
+pub(crate) fn landing(site: &str, page: &str) -> LandingInfo {
+    LandingInfo {
+        referrer: None,
+        page: page.into(),
+        cookie: None,
+        when: Utc::now(),
+        prev_visit: None,
+        site: Some(site.into()),
+        campaign: Default::default(),
+        discussion: None,
+        search_terms: None,
+    }
+}
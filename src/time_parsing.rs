@@ -37,23 +37,23 @@ fn parse_moment_duration(s: &str) -> Result<i64, Error> {
     Ok(hrs + min + sec + ms)
 }
 
-fn extract_ms<'a>(s: &'a str) -> Result<(i64, &'a str), Error> {
+fn extract_ms(s: &str) -> Result<(i64, &str), Error> {
     if !s.ends_with('S') {
         return Err(Error::Other(format!("duration string does not end with S: {}", s)));
     }
     extract_segment(&s[..s.len() - 1], &['.', 'M', 'H', 'T'], 1)
 }
 
-fn extract_seconds<'a>(s: &'a str) -> Result<(i64, &'a str), Error> {
+fn extract_seconds(s: &str) -> Result<(i64, &str), Error> {
     extract_segment(s, &['M', 'H', 'T'], ONE_SECOND)
 
 }
 
-fn extract_minutes<'a>(s: &'a str) -> Result<(i64, &'a str), Error> {
+fn extract_minutes(s: &str) -> Result<(i64, &str), Error> {
     extract_segment(s, &['H', 'T'], ONE_MINUTE)
 }
 
-fn extract_hours<'a>(s: &'a str) -> Result<(i64, &'a str), Error> {
+fn extract_hours(s: &str) -> Result<(i64, &str), Error> {
     extract_segment(s, &['T'], ONE_HOUR)
 }
 
@@ -64,7 +64,7 @@ fn extract_segment<'a>(s: &'a str, targets: &[char], multiplier: i64) -> Result<
                return true
            }
         }
-        return false
+        false
     }) {
         let section = &s[idx+1..];
         debug!(target: "analytics:debug", "extracting section: {}", section);
@@ -81,10 +81,10 @@ where S: Serializer
     serializer.serialize_str(&serialize_duration(*ms))
 }
 
-fn serialize_duration(ms: i64) -> String {
+pub(crate) fn serialize_duration(ms: i64) -> String {
     debug!(target: "analytics:debug","serializing timestamp: {}", ms);
     if ms == 0 {
-        return format!("P0D");
+        return "P0D".to_string();
     }
     let mut remaining = ms;
     let mut hours = 0;
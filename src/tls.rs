@@ -0,0 +1,205 @@
+use std::{
+    error::Error as StdError,
+    fs::read,
+    io::{self, Read, Write},
+    path::Path,
+};
+
+use native_tls::{
+    Certificate,
+    Identity,
+    TlsConnector,
+    TlsStream as NativeStream,
+};
+use postgres::tls::{
+    Stream,
+    TlsHandshake,
+    TlsStream,
+};
+use r2d2_postgres::TlsMode;
+
+use config::{TlsConfig, SslMode};
+use super::Error;
+
+/// Convert the `[db.tls]` section of the config into the mode
+/// the connection pool uses for every new connection
+pub(crate) fn tls_mode(config: &TlsConfig) -> Result<TlsMode, Error> {
+    Ok(match config.mode {
+        SslMode::Disable => TlsMode::None,
+        SslMode::Prefer => TlsMode::Prefer(Box::new(Handshake::new(config)?)),
+        SslMode::Require |
+        SslMode::VerifyFull => TlsMode::Require(Box::new(Handshake::new(config)?)),
+    })
+}
+
+/// A `TlsHandshake` backed by `native-tls`. Postgres' own modes
+/// are mirrored here, `prefer` and `require` only encrypt the
+/// connection while `verify-full` also checks the certificate
+/// chain against the CA bundle and the host name against the
+/// certificate.
+#[derive(Debug)]
+pub(crate) struct Handshake {
+    connector: TlsConnector,
+}
+
+impl Handshake {
+    pub fn new(config: &TlsConfig) -> Result<Self, Error> {
+        let mut builder = TlsConnector::builder();
+        let verify = config.mode == SslMode::VerifyFull;
+        builder.danger_accept_invalid_certs(!verify);
+        builder.danger_accept_invalid_hostnames(!verify);
+        if let Some(ref path) = config.ca_file {
+            for cert in Certificate::stack_from_pem(&read_file(path)?)? {
+                builder.add_root_certificate(cert);
+            }
+        }
+        match (&config.client_cert, &config.client_key) {
+            (Some(cert), Some(key)) => {
+                let identity = Identity::from_pkcs8(&read_file(cert)?, &read_file(key)?)?;
+                builder.identity(identity);
+            },
+            (None, None) => (),
+            _ => return Err(Error::Other("db.tls client_cert and client_key must be provided together".into())),
+        }
+        Ok(Self {
+            connector: builder.build()?,
+        })
+    }
+}
+
+impl TlsHandshake for Handshake {
+    fn tls_handshake(&self, host: &str, stream: Stream) -> Result<Box<dyn TlsStream>, Box<dyn StdError + Sync + Send>> {
+        let stream = self.connector.connect(host, stream).map_err(|e| e.to_string())?;
+        Ok(Box::new(Wrapper(stream)))
+    }
+}
+
+#[derive(Debug)]
+struct Wrapper(NativeStream<Stream>);
+
+impl Read for Wrapper {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.0.read(buf)
+    }
+}
+
+impl Write for Wrapper {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush()
+    }
+}
+
+impl TlsStream for Wrapper {
+    fn get_ref(&self) -> &Stream {
+        self.0.get_ref()
+    }
+
+    fn get_mut(&mut self) -> &mut Stream {
+        self.0.get_mut()
+    }
+}
+
+fn read_file(path: &Path) -> Result<Vec<u8>, Error> {
+    read(path).map_err(|e| Error::Other(format!("Unable to read {}: {}", path.display(), e)))
+}
+
+/// The tests run against a stand in for Postgres that only speaks
+/// enough of the protocol to negotiate TLS and accept the startup
+/// message, using the certificate checked in under `test_certs`.
+#[cfg(test)]
+mod test {
+    use std::{
+        fs::read,
+        io::{Read, Write},
+        net::TcpListener,
+        thread::{self, JoinHandle},
+    };
+
+    use native_tls::{Identity, TlsAcceptor};
+    use postgres::{Connection, TlsMode};
+
+    use config::{SslMode, TlsConfig};
+    use super::Handshake;
+
+    static CA_FILE: &str = "test_certs/server.crt";
+    static KEY_FILE: &str = "test_certs/server.key";
+
+    /// Accept a single connection, answering the `SSLRequest` with
+    /// `S` and, once the handshake succeeds, reading the startup
+    /// message and reporting the session as ready. The handle
+    /// resolves to whether a startup message arrived over TLS.
+    fn fake_server() -> (u16, JoinHandle<bool>) {
+        let cert = read(CA_FILE).expect("Unable to read test certificate");
+        let key = read(KEY_FILE).expect("Unable to read test key");
+        let identity = Identity::from_pkcs8(&cert, &key).expect("Invalid test identity");
+        let acceptor = TlsAcceptor::new(identity).expect("Unable to build acceptor");
+        let listener = TcpListener::bind("127.0.0.1:0").expect("Unable to bind");
+        let port = listener.local_addr().unwrap().port();
+        let handle = thread::spawn(move || {
+            let (mut socket, _) = match listener.accept() {
+                Ok(pair) => pair,
+                Err(_) => return false,
+            };
+            let mut ssl_request = [0; 8];
+            if socket.read_exact(&mut ssl_request).is_err() || socket.write_all(b"S").is_err() {
+                return false;
+            }
+            let mut stream = match acceptor.accept(socket) {
+                Ok(stream) => stream,
+                Err(_) => return false,
+            };
+            let mut len = [0; 4];
+            if stream.read_exact(&mut len).is_err() {
+                return false;
+            }
+            let mut startup = vec![0; u32::from_be_bytes(len) as usize - 4];
+            if stream.read_exact(&mut startup).is_err() {
+                return false;
+            }
+            // AuthenticationOk followed by ReadyForQuery
+            stream.write_all(b"R\0\0\0\x08\0\0\0\0Z\0\0\0\x05I").is_ok()
+        });
+        (port, handle)
+    }
+
+    fn connect(mode: SslMode, ca_file: Option<&str>) -> (Result<Connection, ::postgres::Error>, bool) {
+        let config = TlsConfig {
+            mode,
+            ca_file: ca_file.map(Into::into),
+            ..Default::default()
+        };
+        let handshake = Handshake::new(&config).expect("Unable to build handshake");
+        let (port, server) = fake_server();
+        let conn = Connection::connect(
+            format!("postgres://postgres@localhost:{}/analytics", port),
+            TlsMode::Require(&handshake),
+        );
+        let started = server.join().unwrap();
+        (conn, started)
+    }
+
+    #[test]
+    fn verify_full_self_signed() {
+        let (conn, started) = connect(SslMode::VerifyFull, Some(CA_FILE));
+        conn.expect("Unable to connect with verify-full");
+        assert!(started);
+    }
+
+    #[test]
+    fn require_skips_verification() {
+        let (conn, started) = connect(SslMode::Require, None);
+        assert!(conn.is_ok());
+        assert!(started);
+    }
+
+    #[test]
+    fn verify_full_unknown_ca() {
+        let (conn, started) = connect(SslMode::VerifyFull, None);
+        assert!(conn.is_err());
+        assert!(!started);
+    }
+}
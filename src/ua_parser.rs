@@ -0,0 +1,93 @@
+//! Parsing `User-Agent` headers into the families stored with each
+//! session. This only depends on uap-rust and lru-cache so the
+//! benchmarks can include it directly.
+use std::sync::Mutex;
+
+use lru_cache::LruCache;
+use uap_rust::{client::Client, parser::Parser};
+
+/// The width of `session.user_agent`
+pub(crate) const MAX_LEN: usize = 255;
+
+/// The parts of a `User-Agent` header stored with each session
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct UserAgent {
+    pub browser: String,
+    pub browser_major: Option<String>,
+    pub os: String,
+    /// The major and minor version, `10.15` for macOS Catalina
+    pub os_version: Option<String>,
+    pub device: String,
+}
+
+impl From<Client> for UserAgent {
+    fn from(client: Client) -> Self {
+        let os_version = match (client.os.major, client.os.minor) {
+            (Some(major), Some(minor)) => Some(format!("{}.{}", major, minor)),
+            (major, _) => major,
+        };
+        // the families go in columns as wide as `session.user_agent`
+        let cut = |s: String| stored(&s);
+        Self {
+            browser: cut(client.user_agent.family),
+            browser_major: client.user_agent.major.map(cut),
+            os: cut(client.os.family),
+            os_version: os_version.map(cut),
+            device: cut(client.device.family),
+        }
+    }
+}
+
+/// The raw header as it is stored, cut down to fit the column
+pub(crate) fn stored(ua: &str) -> String {
+    ua.chars().take(MAX_LEN).collect()
+}
+
+/// A shared parser along with the results for recent headers
+pub(crate) struct UserAgents {
+    parser: Parser,
+    cache: Mutex<LruCache<String, UserAgent>>,
+}
+
+impl UserAgents {
+    /// Building the parser compiles every regex in uap-core's
+    /// `regexes.yaml`, `capacity` is how many headers are kept parsed
+    pub fn new(capacity: usize) -> Result<Self, String> {
+        Ok(Self {
+            parser: Parser::new().map_err(|e| e.to_string())?,
+            cache: Mutex::new(LruCache::new(capacity)),
+        })
+    }
+
+    pub fn parse(&self, ua: &str) -> UserAgent {
+        if let Some(agent) = self.cache.lock().ok().and_then(|mut c| c.get_mut(ua).cloned()) {
+            return agent;
+        }
+        // parse without holding the lock, two threads might parse
+        // the same header but neither waits on the other
+        let agent = UserAgent::from(self.parser.parse(ua.to_owned()));
+        if let Ok(mut cache) = self.cache.lock() {
+            cache.insert(ua.to_owned(), agent.clone());
+        }
+        agent
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn cached() {
+        let agents = UserAgents::new(2).unwrap();
+        let firefox = "Mozilla/5.0 (X11; Linux x86_64; rv:120.0) Gecko/20100101 Firefox/120.0";
+        let first = agents.parse(firefox);
+        assert_eq!(agents.cache.lock().unwrap().len(), 1);
+        assert_eq!(agents.parse(firefox), first);
+        agents.parse("curl/8.0");
+        agents.parse("I'm a teapot");
+        let mut cache = agents.cache.lock().unwrap();
+        assert_eq!(cache.len(), 2);
+        assert!(!cache.contains_key(firefox));
+    }
+}
@@ -0,0 +1,209 @@
+use std::collections::HashMap;
+
+use postgres::Connection;
+
+use referrers::{compared, ranked};
+use reports::{Cell, Column, Kind, Table};
+use window::ReportWindow;
+use super::Error;
+
+pub(crate) use ua_parser::{stored, UserAgent};
+use ua_parser::UserAgents;
+#[cfg(test)]
+use ua_parser::MAX_LEN;
+
+/// How many of the most recently seen headers are kept parsed
+const CACHE_SIZE: usize = 1024;
+
+lazy_static! {
+    static ref AGENTS: Result<UserAgents, String> = UserAgents::new(CACHE_SIZE);
+}
+
+/// Build the shared parser, called at startup so a broken parser
+/// is found before the first landing instead of on it
+pub(crate) fn init() -> Result<(), Error> {
+    AGENTS.as_ref()
+        .map(|_| ())
+        .map_err(|e| Error::Other(format!("failed to create UA parser {}", e)))
+}
+
+pub(crate) fn parse(ua: &str) -> Result<UserAgent, Error> {
+    match *AGENTS {
+        Ok(ref agents) => Ok(agents.parse(ua)),
+        Err(ref e) => Err(Error::Other(format!("failed to create UA parser {}", e))),
+    }
+}
+
+/// The broad kind of device a session was on, worked out from
+/// the stored families when the report is built
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub(crate) enum DeviceClass {
+    Desktop,
+    Mobile,
+    Tablet,
+    Bot,
+    Other,
+    /// Sessions recorded before the families were stored, or on
+    /// a named device we can't place like a console or a TV
+    Unknown,
+}
+
+impl DeviceClass {
+    pub fn classify(device: Option<&str>, os: Option<&str>) -> Self {
+        let (device, os) = match (device, os) {
+            (Some(device), Some(os)) => (device, os),
+            _ => return DeviceClass::Unknown,
+        };
+        if device == "Spider" {
+            return DeviceClass::Bot;
+        }
+        if ["iPad", "Tablet", "Kindle", "Galaxy Tab", "Nexus 7", "Nexus 9"].iter().any(|t| device.contains(t)) {
+            return DeviceClass::Tablet;
+        }
+        if ["iOS", "Android", "Windows Phone", "BlackBerry OS", "Firefox OS", "KaiOS"].contains(&os) {
+            return DeviceClass::Mobile;
+        }
+        if os.starts_with("Windows") || ["Mac OS X", "Mac OS", "Linux", "Ubuntu", "Fedora", "Debian", "Chrome OS", "FreeBSD", "OpenBSD"].contains(&os) {
+            return DeviceClass::Desktop;
+        }
+        if device != "Other" {
+            return DeviceClass::Unknown;
+        }
+        DeviceClass::Other
+    }
+}
+
+impl ::std::fmt::Display for DeviceClass {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        match self {
+            DeviceClass::Desktop => "desktop",
+            DeviceClass::Mobile => "mobile",
+            DeviceClass::Tablet => "tablet",
+            DeviceClass::Bot => "bot",
+            DeviceClass::Other => "other",
+            DeviceClass::Unknown => "unknown",
+        }.fmt(f)
+    }
+}
+
+/// The sessions sharing one combination of families and versions
+struct Agent {
+    browser: Option<String>,
+    browser_major: Option<String>,
+    os: Option<String>,
+    os_version: Option<String>,
+    device: Option<String>,
+    sessions: i64,
+}
+
+/// Sessions broken down by browser, operating system and device
+/// class, these are part of the main report. With `previous` the
+/// sessions are paired with that period's.
+pub(crate) fn tables(conn: &Connection, window: &ReportWindow, previous: Option<&ReportWindow>, site: Option<&str>, scope: &str) -> Result<Vec<Table>, Error> {
+    let build = |window: &ReportWindow| -> Result<Vec<Table>, Error> {
+        Ok(breakdown(&agents(conn, window, site)?, scope))
+    };
+    compared(build(window)?, previous.map(build).transpose()?, &[2, 2, 1])
+}
+
+fn agents(conn: &Connection, window: &ReportWindow, site: Option<&str>) -> Result<Vec<Agent>, Error> {
+    Ok(conn.query("SELECT *
+                FROM user_agent_sessions($1, $2, $3)",
+                &[&window.from, &window.to, &site])?
+        .iter()
+        .map(|r| Agent {
+            browser: r.get(0),
+            browser_major: r.get(1),
+            os: r.get(2),
+            os_version: r.get(3),
+            device: r.get(4),
+            sessions: r.get(5),
+        })
+        .collect())
+}
+
+fn breakdown(agents: &[Agent], scope: &str) -> Vec<Table> {
+    let total: i64 = agents.iter().map(|a| a.sessions).sum();
+    let share = |ct: i64| Cell::Percent(ct as f64 / total as f64);
+    let text = |value: Option<String>| value.map(Cell::Text).unwrap_or(Cell::Null);
+    let mut browsers = HashMap::new();
+    let mut systems = HashMap::new();
+    let mut classes = HashMap::new();
+    for agent in agents {
+        *browsers.entry((agent.browser.clone(), agent.browser_major.clone())).or_insert(0) += agent.sessions;
+        *systems.entry((agent.os.clone(), agent.os_version.clone())).or_insert(0) += agent.sessions;
+        let class = DeviceClass::classify(agent.device.as_deref(), agent.os.as_deref());
+        *classes.entry(class).or_insert(0) += agent.sessions;
+    }
+    let mut browser_table = Table::new(format!("{} Browsers", scope), vec![
+        Column::new("Browser", Kind::Text),
+        Column::new("Version", Kind::Text),
+        Column::new("Sessions", Kind::Int),
+        Column::new("Share", Kind::Percent),
+    ]);
+    browser_table.rows = ranked(browsers).into_iter()
+        .map(|((browser, major), ct)| vec![text(browser), text(major), Cell::Int(ct), share(ct)])
+        .collect();
+    let mut os_table = Table::new(format!("{} Operating Systems", scope), vec![
+        Column::new("OS", Kind::Text),
+        Column::new("Version", Kind::Text),
+        Column::new("Sessions", Kind::Int),
+        Column::new("Share", Kind::Percent),
+    ]);
+    os_table.rows = ranked(systems).into_iter()
+        .map(|((os, version), ct)| vec![text(os), text(version), Cell::Int(ct), share(ct)])
+        .collect();
+    let mut class_table = Table::new(format!("{} Devices", scope), vec![
+        Column::new("Device", Kind::Text),
+        Column::new("Sessions", Kind::Int),
+        Column::new("Share", Kind::Percent),
+    ]);
+    class_table.rows = ranked(classes).into_iter()
+        .map(|(class, ct)| vec![Cell::Text(class.to_string()), Cell::Int(ct), share(ct)])
+        .collect();
+    vec![browser_table, os_table, class_table]
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parsing() {
+        let firefox = parse("Mozilla/5.0 (Windows NT 10.0; Win64; x64; rv:120.0) Gecko/20100101 Firefox/120.0").unwrap();
+        assert_eq!(firefox.browser, "Firefox");
+        assert_eq!(firefox.browser_major.as_deref(), Some("120"));
+        assert_eq!(firefox.os, "Windows 10");
+        let iphone = parse("Mozilla/5.0 (iPhone; CPU iPhone OS 16_5 like Mac OS X) AppleWebKit/605.1.15 (KHTML, like Gecko) Version/16.5 Mobile/15E148 Safari/604.1").unwrap();
+        assert_eq!(iphone.os, "iOS");
+        assert_eq!(iphone.os_version.as_deref(), Some("16.5"));
+        assert_eq!(iphone.device, "iPhone");
+        let teapot = parse("I'm a teapot").unwrap();
+        assert_eq!(teapot.browser, "Other");
+        assert_eq!(teapot.browser_major, None);
+        assert_eq!(stored(&"x".repeat(300)).len(), MAX_LEN);
+    }
+
+    #[test]
+    fn classes() {
+        let class = |device, os| DeviceClass::classify(Some(device), Some(os));
+        assert_eq!(class("Other", "Windows 10"), DeviceClass::Desktop);
+        assert_eq!(class("Other", "Mac OS X"), DeviceClass::Desktop);
+        assert_eq!(class("iPhone", "iOS"), DeviceClass::Mobile);
+        assert_eq!(class("iPad", "iOS"), DeviceClass::Tablet);
+        assert_eq!(class("Samsung SM-G960F", "Android"), DeviceClass::Mobile);
+        assert_eq!(class("Spider", "Other"), DeviceClass::Bot);
+        assert_eq!(class("Other", "Other"), DeviceClass::Other);
+        assert_eq!(DeviceClass::classify(None, None), DeviceClass::Unknown);
+        // consoles and TVs aren't phones
+        let parsed = |ua| {
+            let agent = parse(ua).unwrap();
+            DeviceClass::classify(Some(&agent.device), Some(&agent.os))
+        };
+        assert_eq!(parsed("Mozilla/5.0 (PlayStation 4 3.11) AppleWebKit/537.73 (KHTML, like Gecko)"), DeviceClass::Unknown);
+        assert_eq!(parsed("Mozilla/5.0 (Windows NT 10.0; Win64; x64; Xbox; Xbox One) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/70.0.3538.102 Safari/537.36 Edge/18.19041"), DeviceClass::Desktop);
+        assert_eq!(parsed("Mozilla/5.0 (SMART-TV; Linux; Tizen 2.4.0) AppleWebkit/538.1 (KHTML, like Gecko) SamsungBrowser/1.1 TV Safari/538.1"), DeviceClass::Desktop);
+        assert_eq!(parsed("Mozilla/5.0 (Web0S; Linux/SmartTV) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/38.0.2125.122 Safari/537.36 LG Browser/8.00.00(LGE; 60UJ6300-UA; 04.55.06; 1; DTV_W17U); webOS.TV-2017"), DeviceClass::Desktop);
+        assert_eq!(class("Roku", "Other"), DeviceClass::Unknown);
+    }
+}
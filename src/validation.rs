@@ -0,0 +1,265 @@
+use chrono::{DateTime, Duration, Utc};
+use serde_json::Value;
+
+use super::{EventInfo, ExitingInfo, LandingInfo};
+
+/// The width of the `VARCHAR` columns the strings are stored in
+const MAX_LEN: usize = 255;
+/// How far ahead of our clock a client's clock may be
+const MAX_CLOCK_SKEW_MINUTES: i64 = 5;
+/// How old a timestamp may be, queued beacons can arrive late
+const MAX_AGE_DAYS: i64 = 30;
+/// Longer than anyone reasonably leaves a tab open
+const MAX_TIME_ON_PAGE_MS: i64 = 24 * 60 * 60 * 1000;
+/// The size of an event's `properties` once serialized
+const MAX_PROPERTIES_BYTES: usize = 4 * 1024;
+/// How many objects and arrays deep an event's `properties` go,
+/// the top level object counts as the first
+const MAX_PROPERTIES_DEPTH: usize = 4;
+
+/// The problem with a single field of a request body
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct FieldError {
+    pub field: &'static str,
+    pub message: String,
+}
+
+/// The JSON body of an error response, `fields` is only included
+/// when the body parsed but its values were rejected
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct ErrorBody {
+    pub error: String,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub fields: Vec<FieldError>,
+}
+
+impl ErrorBody {
+    pub fn new(error: String) -> Self {
+        Self {
+            error,
+            fields: Vec::new(),
+        }
+    }
+}
+
+impl ::std::fmt::Display for ErrorBody {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        self.error.fmt(f)?;
+        for (i, field) in self.fields.iter().enumerate() {
+            write!(f, "{} {}: {}", if i == 0 { ":" } else { ";" }, field.field, field.message)?;
+        }
+        Ok(())
+    }
+}
+
+/// Check a request body before it gets anywhere near the database
+pub trait Validate {
+    fn validate_at(&self, now: DateTime<Utc>) -> Vec<FieldError>;
+
+    fn validate(&self) -> Result<(), ErrorBody> {
+        let fields = self.validate_at(Utc::now());
+        if fields.is_empty() {
+            Ok(())
+        } else {
+            Err(ErrorBody {
+                error: "invalid request body".to_string(),
+                fields,
+            })
+        }
+    }
+}
+
+impl Validate for LandingInfo {
+    fn validate_at(&self, now: DateTime<Utc>) -> Vec<FieldError> {
+        let mut ret = Vec::new();
+        url("page", Some(&self.page), &mut ret);
+        if let Some(ref referrer) = self.referrer {
+            if !referrer.is_empty() {
+                url("referrer", Some(referrer), &mut ret);
+            }
+        }
+        if let Some(ref site) = self.site {
+            if site.is_empty() {
+                ret.push(error("site", "must not be empty"));
+            }
+            length("site", site, &mut ret);
+        }
+        for (field, value) in &[
+            ("utm_source", &self.campaign.source),
+            ("utm_medium", &self.campaign.medium),
+            ("utm_campaign", &self.campaign.name),
+        ] {
+            if let Some(ref value) = **value {
+                length(field, value, &mut ret);
+            }
+        }
+        timestamp("when", self.when, now, &mut ret);
+        ret
+    }
+}
+
+impl Validate for ExitingInfo {
+    fn validate_at(&self, _now: DateTime<Utc>) -> Vec<FieldError> {
+        let mut ret = Vec::new();
+        if self.time < 0 {
+            ret.push(error("time", "must not be negative"));
+        } else if self.time > MAX_TIME_ON_PAGE_MS {
+            ret.push(error("time", "must be less than 24 hours"));
+        }
+        url("link_clicked", self.link_clicked.as_ref(), &mut ret);
+        ret
+    }
+}
+
+impl Validate for EventInfo {
+    fn validate_at(&self, now: DateTime<Utc>) -> Vec<FieldError> {
+        let mut ret = Vec::new();
+        if self.name.trim().is_empty() {
+            ret.push(error("name", "must not be empty"));
+        }
+        length("name", &self.name, &mut ret);
+        if let Some(ref properties) = self.properties {
+            let size = ::serde_json::to_vec(properties).map(|v| v.len()).unwrap_or(0);
+            if size > MAX_PROPERTIES_BYTES {
+                ret.push(error("properties", &format!("must be at most {} bytes", MAX_PROPERTIES_BYTES)));
+            } else if 1 + properties.values().map(depth).max().unwrap_or(0) > MAX_PROPERTIES_DEPTH {
+                ret.push(error("properties", &format!("must be nested at most {} levels deep", MAX_PROPERTIES_DEPTH)));
+            }
+        }
+        timestamp("when", self.when, now, &mut ret);
+        ret
+    }
+}
+
+/// How many objects and arrays deep `value` goes
+fn depth(value: &Value) -> usize {
+    match value {
+        Value::Array(items) => 1 + items.iter().map(depth).max().unwrap_or(0),
+        Value::Object(fields) => 1 + fields.values().map(depth).max().unwrap_or(0),
+        _ => 0,
+    }
+}
+
+fn error(field: &'static str, message: &str) -> FieldError {
+    FieldError {
+        field,
+        message: message.to_string(),
+    }
+}
+
+fn length(field: &'static str, value: &str, errors: &mut Vec<FieldError>) {
+    if value.chars().count() > MAX_LEN {
+        errors.push(error(field, &format!("must be at most {} characters", MAX_LEN)));
+    }
+}
+
+/// A scheme, `://` and a host without any whitespace
+fn url(field: &'static str, value: Option<&String>, errors: &mut Vec<FieldError>) {
+    let value = match value {
+        Some(value) => value,
+        None => return,
+    };
+    if value.is_empty() {
+        errors.push(error(field, "must not be empty"));
+        return;
+    }
+    length(field, value, errors);
+    let valid = match value.find("://") {
+        Some(idx) => {
+            let scheme = &value[..idx];
+            let host = value[idx + 3..].split(&['/', '?', '#'][..]).next().unwrap_or("");
+            !scheme.is_empty()
+                && scheme.chars().all(|c| c.is_ascii_alphanumeric() || c == '+' || c == '-' || c == '.')
+                && !host.is_empty()
+                && !value.chars().any(char::is_whitespace)
+        },
+        None => false,
+    };
+    if !valid {
+        errors.push(error(field, "must be an absolute url like https://example.com/page"));
+    }
+}
+
+fn timestamp(field: &'static str, value: DateTime<Utc>, now: DateTime<Utc>, errors: &mut Vec<FieldError>) {
+    if value > now + Duration::minutes(MAX_CLOCK_SKEW_MINUTES) {
+        errors.push(error(field, "must not be in the future"));
+    } else if value < now - Duration::days(MAX_AGE_DAYS) {
+        errors.push(error(field, &format!("must be within the last {} days", MAX_AGE_DAYS)));
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use uuid::Uuid;
+
+    fn landing() -> LandingInfo {
+        LandingInfo {
+            referrer: Some("https://news.ycombinator.com/item?id=1".into()),
+            ..::test_support::landing("wiredforge.com", "https://wiredforge.com/blog")
+        }
+    }
+
+    fn fields(errors: Vec<FieldError>) -> Vec<&'static str> {
+        errors.into_iter().map(|e| e.field).collect()
+    }
+
+    #[test]
+    fn landing_fields() {
+        let now = Utc::now();
+        assert!(landing().validate_at(now).is_empty());
+        let mut info = landing();
+        info.page = String::new();
+        info.referrer = Some(format!("https://example.com/{}", "a".repeat(300)));
+        info.when = now + Duration::hours(1);
+        info.site = Some(String::new());
+        assert_eq!(fields(info.validate_at(now)), vec!["page", "referrer", "site", "when"]);
+        for bad in &["wiredforge.com/blog", "https://", "://example.com", "https://exa mple.com", "ht tp://example.com"] {
+            let mut info = landing();
+            info.page = bad.to_string();
+            assert_eq!(fields(info.validate_at(now)), vec!["page"], "{} should be invalid", bad);
+        }
+        let mut old = landing();
+        old.when = now - Duration::days(31);
+        old.referrer = Some(String::new());
+        assert_eq!(fields(old.validate_at(now)), vec!["when"]);
+        let mut campaign = landing();
+        campaign.campaign.medium = Some("email".into());
+        campaign.campaign.name = Some("a".repeat(300));
+        assert_eq!(fields(campaign.validate_at(now)), vec!["utm_campaign"]);
+    }
+
+    #[test]
+    fn exiting_fields() {
+        let mut info = ExitingInfo {
+            visit: Uuid::new_v4(),
+            time: 1000,
+            link_clicked: Some("https://wiredforge.com/projects".into()),
+        };
+        assert!(info.validate().is_ok());
+        info.time = -1;
+        info.link_clicked = Some("projects".into());
+        let err = info.validate().unwrap_err();
+        assert_eq!(err.fields, vec![
+            error("time", "must not be negative"),
+            error("link_clicked", "must be an absolute url like https://example.com/page"),
+        ]);
+        assert_eq!(err.to_string(), "invalid request body: time: must not be negative; link_clicked: must be an absolute url like https://example.com/page");
+    }
+
+    #[test]
+    fn event_fields() {
+        let event = |properties: &str| EventInfo {
+            name: "copied code".into(),
+            properties: ::serde_json::from_str(properties).unwrap(),
+            visit: Uuid::new_v4(),
+            when: Utc::now(),
+        };
+        assert!(event("null").validate().is_ok());
+        assert!(event(r#"{"block": {"lines": [1, [2, 3]]}}"#).validate().is_ok());
+        let deep = event(r#"{"a": {"b": {"c": {"d": {}}}}}"#).validate().unwrap_err();
+        assert_eq!(deep.fields, vec![error("properties", "must be nested at most 4 levels deep")]);
+        let large = event(&format!(r#"{{"code": "{}"}}"#, "x".repeat(MAX_PROPERTIES_BYTES)));
+        assert_eq!(fields(large.validate_at(Utc::now())), vec!["properties"]);
+    }
+}
@@ -0,0 +1,275 @@
+use chrono::{
+    DateTime,
+    Datelike,
+    Duration,
+    NaiveDate,
+    TimeZone,
+    Utc,
+};
+
+/// The longest `last-<n>d` or `last-<n>w` window, anything
+/// longer would overflow the timestamps it is built from
+const MAX_RELATIVE_DAYS: i64 = 100 * 366;
+
+/// The years a window may cover, postgres can't store timestamps
+/// much further out than this
+const MIN_YEAR: i32 = 1;
+const MAX_YEAR: i32 = 9999;
+
+/// The time range a report covers, `from` is inclusive
+/// and `to` is exclusive
+#[derive(Debug, Clone, PartialEq)]
+pub struct ReportWindow {
+    pub from: DateTime<Utc>,
+    pub to: DateTime<Utc>,
+    label: String,
+}
+
+/// The query string accepted by the report endpoints
+#[derive(Deserialize, Debug, Default)]
+pub struct ReportQuery {
+    pub from: Option<String>,
+    pub to: Option<String>,
+    /// Pair the counts in the report with the counts for the
+    /// period of the same length just before the window, tables
+    /// without a count to compare are left as they are and noted
+    /// as not compared
+    #[serde(default)]
+    pub compare: bool,
+    /// `ascii`, `text`, `json` or `csv`, overrides the `Accept` header
+    pub format: Option<String>,
+    /// Only include sessions from this site, with or without
+    /// the scheme or `www.`
+    pub site: Option<String>,
+    /// Only list referrers classified as this channel, used by
+    /// `/analytics/referrers`
+    pub channel: Option<String>,
+    /// Only list referrers from this source, used by
+    /// `/analytics/referrers`
+    pub source: Option<String>,
+}
+
+impl ReportWindow {
+    /// Build the window requested by the path segment and query
+    /// string of a report request. The path may be one of
+    ///
+    /// - `day`, `week` or `month`, the last 1, 7 or 30 days
+    /// - `last-<n>d` or `last-<n>w`, the last n days or weeks
+    /// - `YYYY-MM`, a calendar month
+    /// - `YYYY-MM-DD`, a calendar day
+    /// - `range`, which requires both `from` and `to` in the query
+    ///   as either RFC 3339 timestamps or `YYYY-MM-DD` dates
+    ///
+    /// When `compare` is set the previous period has to be in
+    /// range as well.
+    pub fn from_request(window: &str, query: &ReportQuery) -> Result<Self, String> {
+        let ret = Self::parse_at(window, query, Utc::now())?;
+        if query.compare {
+            ret.previous()?;
+        }
+        Ok(ret)
+    }
+
+    fn parse_at(window: &str, query: &ReportQuery, now: DateTime<Utc>) -> Result<Self, String> {
+        if window == "range" {
+            return match (&query.from, &query.to) {
+                (Some(from), Some(to)) => Self::new(parse_moment(from)?, parse_moment(to)?, None),
+                _ => Err("range reports require both from and to".to_string()),
+            };
+        }
+        if query.from.is_some() || query.to.is_some() {
+            return Err(format!("from and to are only allowed on range reports, not {}", window));
+        }
+        match window {
+            "day" => return Self::last(now, Duration::days(1), "1 Day"),
+            "week" => return Self::last(now, Duration::days(7), "7 Day"),
+            "month" => return Self::last(now, Duration::days(30), "30 Day"),
+            _ => (),
+        }
+        if let Some(spec) = window.strip_prefix("last-") {
+            let (n, unit) = spec.split_at(spec.len().saturating_sub(1));
+            let n: i64 = n.parse().map_err(|_| format!("invalid relative window {}", window))?;
+            if n < 1 {
+                return Err(format!("relative windows must be at least 1, got {}", n));
+            }
+            let (days, label) = match unit {
+                "d" => (n, format!("{} Day", n)),
+                "w" => (n.saturating_mul(7), format!("{} Week", n)),
+                _ => return Err(format!("unknown unit in {}, expected d or w", window)),
+            };
+            if days > MAX_RELATIVE_DAYS {
+                return Err(format!("relative windows are limited to {} days", MAX_RELATIVE_DAYS));
+            }
+            return Self::last(now, Duration::days(days), &label);
+        }
+        if let Ok(day) = NaiveDate::parse_from_str(window, "%Y-%m-%d") {
+            let from = Utc.from_utc_date(&day).and_hms(0, 0, 0);
+            let to = from.checked_add_signed(Duration::days(1))
+                .ok_or_else(|| format!("report window {} is out of range", window))?;
+            return Self::new(from, to, Some(window.to_string()));
+        }
+        if let Ok(first) = NaiveDate::parse_from_str(&format!("{}-01", window), "%Y-%m-%d") {
+            let next = if first.month() == 12 {
+                NaiveDate::from_ymd_opt(first.year() + 1, 1, 1)
+            } else {
+                NaiveDate::from_ymd_opt(first.year(), first.month() + 1, 1)
+            }.ok_or_else(|| format!("report window {} is out of range", window))?;
+            return Self::new(
+                Utc.from_utc_date(&first).and_hms(0, 0, 0),
+                Utc.from_utc_date(&next).and_hms(0, 0, 0),
+                Some(window.to_string()),
+            );
+        }
+        Err(format!("unknown report window {}", window))
+    }
+
+    fn last(now: DateTime<Utc>, len: Duration, label: &str) -> Result<Self, String> {
+        let from = now.checked_sub_signed(len)
+            .ok_or_else(|| format!("the {} window starts out of range", label))?;
+        Self::new(from, now, Some(label.to_string()))
+    }
+
+    fn new(from: DateTime<Utc>, to: DateTime<Utc>, label: Option<String>) -> Result<Self, String> {
+        if from >= to {
+            return Err(format!("from ({}) must be before to ({})", from, to));
+        }
+        if from.year() < MIN_YEAR || to.year() > MAX_YEAR {
+            return Err(format!("report windows must be within the years {} to {}", MIN_YEAR, MAX_YEAR));
+        }
+        let label = label.unwrap_or_else(|| format!("{} to {}", from.format("%Y-%m-%d %H:%M"), to.format("%Y-%m-%d %H:%M")));
+        Ok(Self {
+            from,
+            to,
+            label,
+        })
+    }
+
+    /// The start of every table name in a report, the site when the
+    /// report is limited to one followed by the window
+    pub fn scope(&self, site: Option<&str>) -> String {
+        match site {
+            Some(site) => format!("{} {}", site, self),
+            None => self.to_string(),
+        }
+    }
+
+    /// The window of the same length ending where this one starts,
+    /// fails when that would start before `MIN_YEAR`
+    pub fn previous(&self) -> Result<Self, String> {
+        let len = self.to.signed_duration_since(self.from);
+        let from = self.from.checked_sub_signed(len)
+            .ok_or_else(|| format!("the period before {} is out of range", self.label))?;
+        Self::new(from, self.from, Some(format!("Previous {}", self.label)))
+    }
+}
+
+impl ::std::fmt::Display for ReportWindow {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        self.label.fmt(f)
+    }
+}
+
+fn parse_moment(s: &str) -> Result<DateTime<Utc>, String> {
+    if let Ok(dt) = DateTime::parse_from_rfc3339(s) {
+        return Ok(dt.with_timezone(&Utc));
+    }
+    NaiveDate::parse_from_str(s, "%Y-%m-%d")
+        .map(|d| Utc.from_utc_date(&d).and_hms(0, 0, 0))
+        .map_err(|_| format!("invalid timestamp {}, expected RFC 3339 or YYYY-MM-DD", s))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn now() -> DateTime<Utc> {
+        Utc.ymd(2026, 10, 18).and_hms(12, 0, 0)
+    }
+
+    fn parse(window: &str) -> Result<ReportWindow, String> {
+        ReportWindow::parse_at(window, &ReportQuery::default(), now())
+    }
+
+    #[test]
+    fn named() {
+        let week = parse("week").unwrap();
+        assert_eq!(week.from, Utc.ymd(2026, 10, 11).and_hms(12, 0, 0));
+        assert_eq!(week.to, now());
+        assert_eq!(week.scope(None), "7 Day");
+        assert_eq!(week.scope(Some("wiredforge.com")), "wiredforge.com 7 Day");
+        assert_eq!(parse("last-7d").unwrap().from, week.from);
+        assert_eq!(parse("last-1w").unwrap().from, week.from);
+        assert_eq!(parse("last-90d").unwrap().from, now() - Duration::days(90));
+    }
+
+    #[test]
+    fn calendar() {
+        let month = parse("2026-09").unwrap();
+        assert_eq!(month.from, Utc.ymd(2026, 9, 1).and_hms(0, 0, 0));
+        assert_eq!(month.to, Utc.ymd(2026, 10, 1).and_hms(0, 0, 0));
+        let december = parse("2025-12").unwrap();
+        assert_eq!(december.to, Utc.ymd(2026, 1, 1).and_hms(0, 0, 0));
+        let day = parse("2026-09-15").unwrap();
+        assert_eq!(day.to - day.from, Duration::days(1));
+    }
+
+    #[test]
+    fn range() {
+        let query = ReportQuery {
+            from: Some("2026-09-01".into()),
+            to: Some("2026-09-15T12:00:00Z".into()),
+            compare: true,
+            ..Default::default()
+        };
+        let window = ReportWindow::parse_at("range", &query, now()).unwrap();
+        assert_eq!(window.from, Utc.ymd(2026, 9, 1).and_hms(0, 0, 0));
+        assert_eq!(window.to, Utc.ymd(2026, 9, 15).and_hms(12, 0, 0));
+        let prev = window.previous().unwrap();
+        assert_eq!(prev.to, window.from);
+        assert_eq!(prev.to - prev.from, window.to - window.from);
+    }
+
+    #[test]
+    fn invalid() {
+        for bad in &["year", "last-d", "last-0d", "last-5y", "2026-13", "2026-02-30", ""] {
+            assert!(parse(bad).is_err(), "{} should not parse", bad);
+        }
+        let backwards = ReportQuery {
+            from: Some("2026-09-15".into()),
+            to: Some("2026-09-01".into()),
+            compare: false,
+            ..Default::default()
+        };
+        assert!(ReportWindow::parse_at("range", &backwards, now()).is_err());
+        let missing = ReportQuery {
+            from: Some("2026-09-15".into()),
+            ..Default::default()
+        };
+        assert!(ReportWindow::parse_at("range", &missing, now()).is_err());
+        assert!(ReportWindow::parse_at("week", &missing, now()).is_err());
+    }
+
+    #[test]
+    fn out_of_range() {
+        for bad in &["last-1000000000d", "last-9223372036854775807d", "last-1000000000000000w", "last-5300w"] {
+            assert!(parse(bad).is_err(), "{} should not parse", bad);
+        }
+        assert!(parse("last-36600d").is_ok());
+        let extreme = ReportQuery {
+            from: Some("-200000-01-01".into()),
+            to: Some("+200000-01-01".into()),
+            compare: true,
+            ..Default::default()
+        };
+        assert!(ReportWindow::parse_at("range", &extreme, now()).is_err());
+        let everything = ReportQuery {
+            from: Some("0001-01-01".into()),
+            to: Some("9999-12-31".into()),
+            compare: true,
+            ..Default::default()
+        };
+        let window = ReportWindow::parse_at("range", &everything, now()).unwrap();
+        assert!(window.previous().is_err());
+        assert!(ReportWindow::from_request("range", &everything).is_err());
+    }
+}